@@ -1,7 +1,11 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use tauri::{AppHandle, Emitter, Manager};
-use tauri_plugin_global_shortcut::{Code, GlobalShortcutExt, Modifiers, Shortcut, ShortcutState};
+use tauri_plugin_notification::NotificationExt;
+use tauri_plugin_opener::OpenerExt;
+use tauri_plugin_global_shortcut::{Code, GlobalShortcutExt, Modifiers, Shortcut, ShortcutEvent, ShortcutState};
 use enigo::{Direction, Enigo, Key, Keyboard, Settings};
 use serde::{Deserialize, Serialize};
 
@@ -9,24 +13,265 @@ use serde::{Deserialize, Serialize};
 
 struct PrevWindow(Mutex<isize>);
 struct SpellStore(Mutex<Vec<LoadedSpell>>);
-struct CollectionsDir(PathBuf);
-struct SelectedText(Mutex<String>);
+struct CollectionStore(Mutex<Vec<LoadedCollection>>);
+// Collection folders whose index failed to load, or that have spells which
+// were skipped, with the reason.
+struct LoadErrors(Mutex<Vec<(PathBuf, String)>>);
+struct LoadDiagnostics(Mutex<Vec<LoadDiagnostic>>);
+struct LastLoadStats(Mutex<LoadStats>);
+struct CollectionsDirs(Mutex<Vec<PathBuf>>);
+struct SelectedInput(Mutex<SpellInput>);
+// What was on the clipboard when the shortcut was pressed, for inputFrom
+// "clipboard". Kept raw: an image is only encoded once a spell reads it.
+struct ClipboardInput(Mutex<Option<Arc<ClipboardSnapshot>>>);
+// Clipboard contents from before the shortcut's simulated copy replaced them with the selection.
+struct OriginalClipboard(Mutex<Option<Arc<ClipboardSnapshot>>>);
+struct ConfigStore(Mutex<AppConfig>);
+struct ActiveShortcut(Mutex<Shortcut>);
+struct StatsStore(Mutex<HashMap<String, SpellStats>>);
+// Triggers the user starred, persisted in favorites.json.
+struct Favorites(Mutex<HashSet<String>>);
+// Set from the tray to ignore the shortcut for a while (not persisted).
+struct Paused(Mutex<bool>);
+// The stream currently running, if any.
+struct ActiveStream(Mutex<Option<StreamHandle>>);
+// Every stream still running, by trigger, for cancel_spell.
+struct RunningProcesses(Mutex<HashMap<String, StreamHandle>>);
+// Set while a spell runs, so a second one can't interleave its typing or
+// clipboard writes with the first. Held through a SpellGuard.
+struct RunningSpell(Arc<AtomicBool>);
+struct WatcherHandle(Mutex<Option<notify::RecommendedWatcher>>);
 
 // ---- Data structures ----
 
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "HotkeyRepr", into = "String")]
+struct HotkeyConfig {
+    modifiers: Vec<String>,
+    code: String,
+}
+
+impl Default for HotkeyConfig {
+    fn default() -> Self {
+        HotkeyConfig { modifiers: vec!["Ctrl".to_string()], code: "Space".to_string() }
+    }
+}
+
+// The hotkey may be written either as "Ctrl+Alt+Space" or as
+// { "modifiers": ["Ctrl", "Alt"], "code": "Space" }. It is always saved in the
+// string form.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HotkeyRepr {
+    Text(String),
+    Parts { modifiers: Vec<String>, code: String },
+}
+
+impl From<HotkeyRepr> for HotkeyConfig {
+    fn from(repr: HotkeyRepr) -> Self {
+        match repr {
+            HotkeyRepr::Text(text) => {
+                let mut tokens: Vec<String> = text.split('+').map(|t| t.trim().to_string()).collect();
+                let code = tokens.pop().unwrap_or_default();
+                HotkeyConfig { modifiers: tokens, code }
+            }
+            HotkeyRepr::Parts { modifiers, code } => HotkeyConfig { modifiers, code },
+        }
+    }
+}
+
+impl From<HotkeyConfig> for String {
+    fn from(hotkey: HotkeyConfig) -> Self {
+        hotkey.to_string()
+    }
+}
+
+impl std::fmt::Display for HotkeyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for modifier in &self.modifiers {
+            write!(f, "{}+", modifier)?;
+        }
+        write!(f, "{}", self.code)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct AppConfig {
+    #[serde(alias = "shortcut")]
+    hotkey: HotkeyConfig,
+    #[serde(rename = "collectionsDirs")]
+    collections_dirs: Vec<PathBuf>,
+    // Wait after refocusing the previous window before pasting or typing.
+    #[serde(rename = "pasteDelayMs", alias = "restoreDelayMs")]
+    paste_delay_ms: u64,
+    // Wait after the simulated copy for the clipboard to update.
+    #[serde(rename = "copySettleMs", alias = "copyDelayMs")]
+    copy_settle_ms: u64,
+    // How often streamed output is flushed to the preview or typed.
+    #[serde(rename = "streamFlushMs")]
+    stream_flush_ms: u64,
+    // Whether paste spells put the previous clipboard back afterwards, unless
+    // the spell sets preserveClipboard itself.
+    #[serde(rename = "restoreClipboard")]
+    restore_clipboard: bool,
+    // Wait after a paste before the previous clipboard is put back.
+    #[serde(rename = "clipboardRestoreDelayMs")]
+    clipboard_restore_delay_ms: u64,
+    // URLs of index.json files loaded as extra collections.
+    #[serde(rename = "remoteCollections")]
+    remote_collections: Vec<String>,
+    // Refuse to load when two spells share a trigger instead of renaming one.
+    #[serde(rename = "strictMode", alias = "strict_mode")]
+    strict_mode: bool,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            hotkey: HotkeyConfig::default(),
+            collections_dirs: vec![get_collections_dir()],
+            paste_delay_ms: 50,
+            copy_settle_ms: 100,
+            stream_flush_ms: 200,
+            restore_clipboard: false,
+            clipboard_restore_delay_ms: 200,
+            remote_collections: Vec::new(),
+            strict_mode: false,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct IndexEntry {
-    default: String,
+    default: Option<String>,
+    windows: Option<String>,
+    macos: Option<String>,
+    linux: Option<String>,
+    // Runs `interpreter script` directly, without a shell. The script path is
+    // relative to the collection folder. Takes the place of the commands above.
+    interpreter: Option<String>,
+    script: Option<PathBuf>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+#[derive(Clone)]
+struct ScriptEntry {
+    interpreter: String,
+    script: PathBuf,
 }
 
-#[derive(Deserialize, Clone, PartialEq, Default)]
+// A value the user is asked for before the spell runs. The entry command
+// refers to it as $NAME and the spell also gets it as an environment variable.
+#[derive(Deserialize, Serialize, Clone)]
+struct ParamDef {
+    name: String,
+    prompt: String,
+    default: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
 #[serde(rename_all = "lowercase")]
 enum OutputMode {
     None,
     Clipboard,
     Preview,
+    // Fast, but overwrites the clipboard (see preserveClipboard).
     #[default]
     Paste,
+    // Types the output as keystrokes. Leaves the clipboard alone, but is slower
+    // on long output and can be mangled by an active IME (e.g. CJK input).
+    Type,
+    // Shows the output as a system notification titled with the trigger.
+    Notify,
+    // Writes the output to the spell's outputPath.
+    File,
+    // Types the output right after / before the selection, keeping it.
+    Append,
+    Prepend,
+    // Pastes the output as rich text, with a tag-stripped plain-text fallback.
+    // Whether the HTML is used is up to the target app: plain-text editors
+    // (and terminals) always get the fallback.
+    Html,
+    // Adds the output to the end of the clipboard text, after appendSeparator.
+    // With streamMode the output is buffered and appended once the spell exits.
+    #[serde(rename = "clipboardAppend", alias = "clipboard_append")]
+    ClipboardAppend,
+}
+
+// How the frontend should render preview output. Rendering is left to the webview.
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum PreviewFormat {
+    #[default]
+    Plain,
+    Markdown,
+    Html,
+}
+
+// What paste output does with the selection it lands on.
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum PasteMode {
+    #[default]
+    Replace,
+    // Pastes the selection followed by the output (or the other way round).
+    Append,
+    Prepend,
+}
+
+// Where a spell's input comes from. "clipboard" takes whatever was on the
+// clipboard when the shortcut was pressed, whether or not anything was selected.
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum InputFrom {
+    #[default]
+    Selection,
+    Clipboard,
+}
+
+// "cmd", "pwsh", "powershell" and "sh" get their usual command flag. Any other
+// value names an interpreter (e.g. "python3") that is run with shellArgs
+// followed by the entry command as a single argument.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(from = "String")]
+enum Shell {
+    Cmd,
+    Pwsh,
+    Powershell,
+    Sh,
+    Custom(String),
+}
+
+impl From<String> for Shell {
+    fn from(name: String) -> Self {
+        match name.to_lowercase().as_str() {
+            "cmd" => Shell::Cmd,
+            "pwsh" => Shell::Pwsh,
+            "powershell" => Shell::Powershell,
+            "sh" => Shell::Sh,
+            _ => Shell::Custom(name),
+        }
+    }
+}
+
+impl Shell {
+    fn platform_default() -> Self {
+        if cfg!(target_os = "windows") { Shell::Cmd } else { Shell::Sh }
+    }
+
+    fn program(&self) -> &str {
+        match self {
+            Shell::Cmd => "cmd",
+            Shell::Pwsh => "pwsh",
+            Shell::Powershell => "powershell",
+            Shell::Sh => "sh",
+            Shell::Custom(program) => program,
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -35,42 +280,383 @@ struct IndexSettings {
     output_mode: OutputMode,
     #[serde(rename = "streamMode", default)]
     stream_mode: bool,
+    // Ask before running, for spells that can do damage.
+    #[serde(default)]
+    confirm: bool,
+    #[serde(rename = "previewFormat", default)]
+    preview_format: PreviewFormat,
+    #[serde(rename = "pasteMode", default)]
+    paste_mode: PasteMode,
+    // Put between selection and output by append/prepend paste.
+    #[serde(rename = "pasteSeparator")]
+    paste_separator: Option<String>,
+    #[serde(rename = "appendSeparator", alias = "append_separator")]
+    append_separator: Option<String>,
+    #[serde(rename = "inputFrom", alias = "input_from", default)]
+    input_from: InputFrom,
+    #[serde(rename = "timeoutMs")]
+    timeout_ms: Option<u64>,
+    #[serde(rename = "timeoutSecs")]
+    timeout_secs: Option<u64>,
+    #[serde(rename = "preserveClipboard")]
+    preserve_clipboard: Option<bool>,
+    #[serde(rename = "streamFlushMs", alias = "flushIntervalMs", alias = "flush_interval_ms")]
+    stream_flush_ms: Option<u64>,
+    #[serde(rename = "typeDelayMs")]
+    type_delay_ms: Option<u64>,
+    shell: Option<Shell>,
+    #[serde(rename = "shellArgs", alias = "shell_args")]
+    shell_args: Option<Vec<String>>,
+    #[serde(rename = "outputPath")]
+    output_path: Option<String>,
+    // Adds each run's output to the end of outputPath instead of replacing it.
+    #[serde(rename = "outputAppend", alias = "output_append", default)]
+    output_append: bool,
+    // Merged over entry.env, winning on conflicts.
+    #[serde(default)]
+    env: BTreeMap<String, String>,
 }
 
 #[derive(Deserialize)]
 struct SpellDef {
     trigger: String,
     description: Option<String>,
+    category: Option<String>,
     entry: IndexEntry,
     settings: Option<IndexSettings>,
+    enabled: Option<bool>,
+    order: Option<i64>,
+    // Higher comes first in get_spells.
+    priority: Option<i32>,
+    // Extra triggers that run the same spell.
+    aliases: Option<Vec<String>>,
+    // An emoji, an icon name, or a path to an image relative to the collection.
+    icon: Option<String>,
+    params: Option<Vec<ParamDef>>,
+    // Shell commands run just before and just after the spell's entry.
+    before: Option<String>,
+    after: Option<String>,
+    // Stop the spell when its before hook fails instead of only reporting it.
+    #[serde(rename = "abortOnHookFailure", alias = "abort_on_hook_failure", default)]
+    abort_on_hook_failure: bool,
+}
+
+#[derive(Deserialize, Clone, Default)]
+struct CollectionMeta {
+    name: Option<String>,
+    description: Option<String>,
+    author: Option<String>,
+    version: Option<String>,
+    // Collections and spells with an order come first, lowest first.
+    order: Option<i64>,
 }
 
 #[derive(Deserialize)]
 struct CollectionIndex {
+    #[serde(flatten)]
+    meta: CollectionMeta,
     spells: Vec<SpellDef>,
 }
 
+// Which index file a collection was loaded from.
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum CollectionFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+// Where collections come from: a directory of collection folders, or a single
+// index.json downloaded from a URL into its own cache folder.
+enum CollectionSource {
+    Directory(PathBuf),
+    Remote { url: String, cache_path: PathBuf },
+}
+
+impl CollectionSource {
+    // The folder this source's collections live under.
+    fn root(&self) -> &Path {
+        match self {
+            CollectionSource::Directory(dir) => dir,
+            CollectionSource::Remote { cache_path, .. } => cache_path.parent().unwrap_or(cache_path),
+        }
+    }
+}
+
+struct LoadedCollection {
+    dir: PathBuf,
+    meta: CollectionMeta,
+    collection_format: CollectionFormat,
+    spells: Vec<LoadedSpell>,
+    // Spells with "enabled": false, which are not loaded at all.
+    disabled: Vec<DisabledSpell>,
+}
+
+#[derive(Serialize, Clone)]
+struct DisabledSpell {
+    trigger: String,
+    description: Option<String>,
+    // Position in the index's "spells" array, for enabling it again.
+    #[serde(skip)]
+    index_position: usize,
+}
+
+#[derive(Clone)]
 struct LoadedSpell {
     trigger: String,
+    // Set on alias entries: the trigger of the spell they stand in for.
+    primary_trigger: Option<String>,
+    aliases: Vec<String>,
     description: Option<String>,
+    category: Option<String>,
+    icon: Option<String>,
+    collection_name: String,
     collection_dir: PathBuf,
+    // Empty when the spell runs a script instead.
     entry_cmd: String,
+    script: Option<ScriptEntry>,
+    env: BTreeMap<String, String>,
+    args: Vec<String>,
     output_mode: OutputMode,
     stream_mode: bool,
+    confirm: bool,
+    preview_format: PreviewFormat,
+    paste_mode: PasteMode,
+    paste_separator: Option<String>,
+    input_from: InputFrom,
+    // Between the clipboard text and the output in clipboardAppend; "\n" if unset.
+    append_separator: Option<String>,
+    timeout_ms: Option<u64>,
+    // None follows the app-wide restoreClipboard setting.
+    preserve_clipboard: Option<bool>,
+    stream_flush_ms: Option<u64>,
+    // Pause between typed graphemes; None types each batch at once.
+    type_delay_ms: Option<u64>,
+    shell: Shell,
+    shell_args: Vec<String>,
+    output_path: Option<String>,
+    output_append: bool,
+    // Position in the index's "spells" array, for writing changes back.
+    index_position: usize,
+    priority: i32,
+    params: Vec<ParamDef>,
+    // Filled in from the user's answers just before the spell runs.
+    param_values: BTreeMap<String, String>,
+    before_hook: Option<String>,
+    after_hook: Option<String>,
+    abort_on_hook_failure: bool,
 }
 
 #[derive(Serialize, Clone)]
 struct SpellInfo {
     trigger: String,
     description: Option<String>,
+    category: Option<String>,
+    icon: Option<String>,
+    collection: String,
+    // The collection's folder, for grouping spells from same-named collections.
+    collection_path: PathBuf,
+    primary_trigger: Option<String>,
+    aliases: Vec<String>,
+    output_mode: OutputMode,
+    stream_mode: bool,
+    run_count: u64,
+    is_favorite: bool,
+}
+
+// Everything describe_spell reports about how a spell would run.
+#[derive(Serialize)]
+struct SpellDetail {
+    trigger: String,
+    primary_trigger: Option<String>,
+    // The command for this platform; empty for script spells.
+    entry_cmd: String,
+    shell: String,
+    shell_args: Vec<String>,
+    interpreter: Option<String>,
+    script: Option<PathBuf>,
+    args: Vec<String>,
+    // As written in the index; ${VAR} is expanded only when the spell runs.
+    env: BTreeMap<String, String>,
+    collection_dir: PathBuf,
+    output_mode: OutputMode,
+    stream_mode: bool,
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SpellStats {
+    trigger: String,
+    run_count: u64,
+    // Unix time in seconds.
+    last_run_at: Option<u64>,
+}
+
+// Bucket name for spells that don't set a category.
+const UNCATEGORIZED: &str = "uncategorized";
+
+// Orderings get_spells can return. Priority ties are broken alphabetically by
+// trigger; the other sorts are stable, so their ties keep the loaded order.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum SortMode {
+    // Highest priority first.
+    #[default]
+    Priority,
+    Trigger,
+    // Most recently run first; never-run spells last.
+    LastUsed,
+    RunCount,
+}
+
+// How get_recent_spells ranks spells.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum RecentOrder {
+    // Run count decayed by age, so habits outrank one-offs.
+    #[default]
+    Frecency,
+    // Strictly most recently run first.
+    LastUsed,
+}
+
+// A trigger defined more than once. `first` is the collection the trigger was
+// first seen in, `second` the one shadowing it (the same folder when a
+// collection repeats its own trigger).
+#[derive(Serialize, Clone)]
+struct LoadDiagnostic {
+    trigger: String,
+    first: PathBuf,
+    second: PathBuf,
+}
+
+#[derive(Serialize, Clone, Copy, Default)]
+struct LoadStats {
+    collections_loaded: usize,
+    // Alias entries included.
+    spells_loaded: usize,
+    elapsed_ms: u64,
+}
+
+struct LoadResult {
+    collections: Vec<LoadedCollection>,
+    // Collection folders that failed, or have spells that were skipped.
+    errors: Vec<(PathBuf, String)>,
+    diagnostics: Vec<LoadDiagnostic>,
+    stats: LoadStats,
+}
+
+#[derive(Serialize, Clone)]
+struct CollectionInfo {
+    // Falls back to the folder name when the index has no "name".
+    name: String,
+    description: Option<String>,
+    author: Option<String>,
+    version: Option<String>,
+    path: PathBuf,
+    spell_count: usize,
+    format: CollectionFormat,
+    disabled_spells: Vec<DisabledSpell>,
 }
 
 #[derive(Serialize, Clone)]
 #[serde(tag = "mode", rename_all = "snake_case")]
 enum SpellResult {
     Done,
-    Preview { content: String },
+    Preview { content: String, format: PreviewFormat },
     Stream,
+    // Another spell is still running; nothing was started.
+    Busy,
+    // The spell is marked confirm; run it with confirm_spell.
+    NeedsConfirm { trigger: String },
+}
+
+// What a spell reads on stdin: the selected text, or a copied image as PNG
+// bytes. SPELLPASTE_INPUT_KIND tells the spell which one it got.
+#[derive(Clone)]
+enum SpellInput {
+    Text(String),
+    Image(Vec<u8>),
+}
+
+impl Default for SpellInput {
+    fn default() -> Self {
+        SpellInput::Text(String::new())
+    }
+}
+
+impl SpellInput {
+    // The text for {{input}}; images have none.
+    fn text(&self) -> &str {
+        match self {
+            SpellInput::Text(text) => text,
+            SpellInput::Image(_) => "",
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            SpellInput::Text(text) => text.as_bytes(),
+            SpellInput::Image(png) => png,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            SpellInput::Text(_) => "text",
+            SpellInput::Image(_) => "image",
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes().is_empty()
+    }
+}
+
+// Why a spell could not be run or did not finish. Tagged with "mode" like
+// SpellResult, so the frontend can tell a timeout from a missing spell.
+#[derive(Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+enum SpellError {
+    NotFound { trigger: String },
+    // The spell exited unsuccessfully. `stderr` is its error output (or the exit
+    // status when it printed none); `stdout` holds whatever it printed before failing.
+    ExecutionFailed { code: Option<i32>, stderr: String, stdout: String },
+    Timeout { timeout_ms: u64 },
+    // A before hook failed on a spell with abortOnHookFailure.
+    HookFailed { message: String },
+    IoError { message: String },
+    // Enigo could not start; see input_simulator.
+    InputUnavailable { message: String },
+    // Anything else: missing params, a bad setting.
+    Invalid { message: String },
+}
+
+impl std::fmt::Display for SpellError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpellError::NotFound { trigger } => write!(f, "Spell '{}' not found", trigger),
+            SpellError::ExecutionFailed { stderr, .. } => f.write_str(stderr),
+            SpellError::Timeout { timeout_ms } => write!(f, "spell timed out after {} ms", timeout_ms),
+            SpellError::HookFailed { message }
+            | SpellError::IoError { message }
+            | SpellError::InputUnavailable { message }
+            | SpellError::Invalid { message } => f.write_str(message),
+        }
+    }
+}
+
+impl From<String> for SpellError {
+    fn from(message: String) -> Self {
+        SpellError::Invalid { message }
+    }
+}
+
+impl From<std::io::Error> for SpellError {
+    fn from(e: std::io::Error) -> Self {
+        SpellError::IoError { message: e.to_string() }
+    }
 }
 
 // ---- macOS platform module ----
@@ -139,9 +725,172 @@ mod macos {
     }
 }
 
+// ---- Linux platform module ----
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xfixes::{ConnectionExt as _, SelectionEventMask};
+    use x11rb::protocol::xproto::{
+        Atom, AtomEnum, ClientMessageEvent, ConfigureWindowAux, ConnectionExt as _, EventMask,
+        InputFocus, KeyButMask, StackMode, Window,
+    };
+    use x11rb::protocol::Event;
+    use x11rb::rust_connection::RustConnection;
+
+    // Bumped by the XFixes watcher thread on every CLIPBOARD ownership change,
+    // mirroring NSPasteboard.changeCount / GetClipboardSequenceNumber.
+    static CLIPBOARD_CHANGES: AtomicU64 = AtomicU64::new(0);
+
+    pub fn is_wayland() -> bool {
+        std::env::var_os("WAYLAND_DISPLAY").is_some()
+    }
+
+    fn connect() -> Option<(RustConnection, Window)> {
+        let (conn, screen_num) = x11rb::connect(None).ok()?;
+        let root = conn.setup().roots[screen_num].root;
+        Some((conn, root))
+    }
+
+    fn intern(conn: &RustConnection, name: &[u8]) -> Option<Atom> {
+        Some(conn.intern_atom(false, name).ok()?.reply().ok()?.atom)
+    }
+
+    pub fn get_active_window() -> u32 {
+        let Some((conn, root)) = connect() else { return 0 };
+        let active = intern(&conn, b"_NET_ACTIVE_WINDOW")
+            .and_then(|atom| conn.get_property(false, root, atom, AtomEnum::WINDOW, 0, 1).ok())
+            .and_then(|cookie| cookie.reply().ok())
+            .and_then(|reply| reply.value32().and_then(|mut v| v.next()))
+            .unwrap_or(0);
+        if active != 0 { return active; }
+
+        // Window managers without EWMH support don't publish _NET_ACTIVE_WINDOW;
+        // fall back to the raw input focus, skipping the None/PointerRoot sentinels.
+        conn.get_input_focus()
+            .ok()
+            .and_then(|cookie| cookie.reply().ok())
+            .map(|reply| reply.focus)
+            .filter(|&focus| focus > 1 && focus != root)
+            .unwrap_or(0)
+    }
+
+    pub fn activate_window(xid: u32) {
+        let Some((conn, root)) = connect() else { return };
+        let Some(net_active_window) = intern(&conn, b"_NET_ACTIVE_WINDOW") else { return };
+
+        // Source indication 2 (pager) tells the WM this is a deliberate user request,
+        // which bypasses focus-stealing prevention.
+        let event = ClientMessageEvent::new(
+            32,
+            xid,
+            net_active_window,
+            [2, x11rb::CURRENT_TIME, 0, 0, 0],
+        );
+        let _ = conn.send_event(
+            false,
+            root,
+            EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY,
+            event,
+        );
+        let _ = conn.configure_window(xid, &ConfigureWindowAux::new().stack_mode(StackMode::ABOVE));
+        let _ = conn.set_input_focus(InputFocus::PARENT, xid, x11rb::CURRENT_TIME);
+        let _ = conn.flush();
+    }
+
+    // The shortcut fires on key press, so its modifiers are usually still down
+    // when the copy is simulated. XTest events mix with them (Ctrl+Alt+C instead
+    // of Ctrl+C), and releasing Ctrl afterwards fights the user's own key. Polls
+    // until no modifier is held; returns false if they still are at the timeout.
+    pub fn wait_for_modifiers_released(timeout: std::time::Duration) -> bool {
+        let Some((conn, root)) = connect() else { return true };
+        let modifiers = u16::from(KeyButMask::SHIFT)
+            | u16::from(KeyButMask::CONTROL)
+            | u16::from(KeyButMask::MOD1)
+            | u16::from(KeyButMask::MOD4);
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let held = conn.query_pointer(root)
+                .ok()
+                .and_then(|cookie| cookie.reply().ok())
+                .is_some_and(|reply| u16::from(reply.mask) & modifiers != 0);
+            if !held { return true; }
+            if std::time::Instant::now() >= deadline { return false; }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    // Wayland has no way to synthesize Ctrl+C into another client, so copy the
+    // PRIMARY selection (whatever is highlighted) onto the clipboard via wl-clipboard.
+    pub fn copy_primary_selection() {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let Ok(output) = Command::new("wl-paste")
+            .args(["--primary", "--no-newline"])
+            .stderr(Stdio::null())
+            .output()
+        else { return };
+        if !output.status.success() || output.stdout.is_empty() { return; }
+
+        let Ok(mut child) = Command::new("wl-copy")
+            .stdin(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        else { return };
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(&output.stdout);
+        }
+        if child.wait().map(|s| s.success()).unwrap_or(false) {
+            CLIPBOARD_CHANGES.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Text highlighted in any X11/XWayland client, without needing a copy.
+    pub fn read_primary_selection() -> Option<String> {
+        use arboard::{GetExtLinux, LinuxClipboardKind};
+
+        let mut clipboard = arboard::Clipboard::new().ok()?;
+        let text = clipboard.get().clipboard(LinuxClipboardKind::Primary).text().ok()?;
+        if text.is_empty() { None } else { Some(text) }
+    }
+
+    pub fn clipboard_change_count() -> u64 {
+        CLIPBOARD_CHANGES.load(Ordering::SeqCst)
+    }
+
+    pub fn start_clipboard_watcher() {
+        std::thread::spawn(|| {
+            let Some((conn, root)) = connect() else { return };
+            if conn.xfixes_query_version(5, 0).ok().and_then(|c| c.reply().ok()).is_none() {
+                return;
+            }
+            let Some(clipboard) = intern(&conn, b"CLIPBOARD") else { return };
+            let _ = conn.xfixes_select_selection_input(
+                root,
+                clipboard,
+                SelectionEventMask::SET_SELECTION_OWNER,
+            );
+            let _ = conn.flush();
+            while let Ok(event) = conn.wait_for_event() {
+                if let Event::XfixesSelectionNotify(_) = event {
+                    CLIPBOARD_CHANGES.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+    }
+}
+
 // ---- OS helpers ----
 
-fn get_collections_dir() -> PathBuf {
+// The one place warnings are written: problems that don't stop anything but
+// are worth finding in the log later.
+fn log_warning(message: impl std::fmt::Display) {
+    eprintln!("spellpaste: {}", message);
+}
+
+fn get_home_dir() -> PathBuf {
     #[cfg(target_os = "windows")]
     let home = std::env::var("USERPROFILE")
         .map(PathBuf::from)
@@ -150,20 +899,81 @@ fn get_collections_dir() -> PathBuf {
     let home = std::env::var("HOME")
         .map(PathBuf::from)
         .unwrap_or_else(|_| PathBuf::from("/tmp"));
-    home.join(".spellpaste").join("collections")
+    home
+}
+
+fn get_app_dir() -> PathBuf {
+    get_home_dir().join(".spellpaste")
+}
+
+fn get_collections_dir() -> PathBuf {
+    get_app_dir().join("collections")
+}
+
+fn get_config_path() -> PathBuf {
+    get_app_dir().join("config.json")
+}
+
+fn get_stats_path() -> PathBuf {
+    get_app_dir().join("stats.json")
+}
+
+fn get_favorites_path() -> PathBuf {
+    get_app_dir().join("favorites.json")
+}
+
+// The HWND (Windows), frontmost pid (macOS) or X11 window id (Linux) that
+// restore_prev_window takes; 0 when unknown.
+fn foreground_window() -> isize {
+    #[cfg(target_os = "windows")]
+    return unsafe { winapi::um::winuser::GetForegroundWindow() } as isize;
+
+    #[cfg(target_os = "macos")]
+    return unsafe { macos::get_frontmost_pid() } as isize;
+
+    #[cfg(target_os = "linux")]
+    return linux::get_active_window() as isize;
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+    return 0;
 }
 
-fn save_prev_window(state: &PrevWindow) {
+// Whether a foreground_window value belongs to this app.
+fn is_own_window(val: isize) -> bool {
     #[cfg(target_os = "windows")]
     {
-        let hwnd = unsafe { winapi::um::winuser::GetForegroundWindow() };
-        *state.0.lock().unwrap() = hwnd as isize;
+        let mut pid = 0;
+        unsafe { winapi::um::winuser::GetWindowThreadProcessId(val as winapi::shared::windef::HWND, &mut pid) };
+        pid == std::process::id()
     }
     #[cfg(target_os = "macos")]
+    return val as u32 == std::process::id();
+
+    // X11 would need _NET_WM_PID for this; treat every window as someone else's.
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
     {
-        let pid = unsafe { macos::get_frontmost_pid() };
-        *state.0.lock().unwrap() = pid as isize;
+        let _ = val;
+        false
+    }
+}
+
+// Native Wayland windows are invisible to X11, so their focus can't be saved
+// or restored. That is reported once per session as a notification instead
+// of silently doing nothing.
+fn save_prev_window(app: &AppHandle, state: &PrevWindow) {
+    let val = foreground_window();
+    #[cfg(target_os = "linux")]
+    if val == 0 && linux::is_wayland() {
+        static REPORTED: AtomicBool = AtomicBool::new(false);
+        let message = "Spellpaste can't restore focus to Wayland windows, so output goes wherever focus lands when the palette closes.";
+        if !REPORTED.swap(true, Ordering::SeqCst) {
+            log_warning("focused window is not visible to X11 on Wayland; focus will not be restored");
+            let _ = app.notification().builder().title("Spellpaste").body(message).show();
+        }
     }
+    #[cfg(not(target_os = "linux"))]
+    let _ = app;
+    *state.0.lock().unwrap() = val;
 }
 
 fn restore_prev_window(val: isize) {
@@ -181,12 +991,35 @@ fn restore_prev_window(val: isize) {
             macos::activate_pid(val as i32);
         }
     }
+    #[cfg(target_os = "linux")]
+    if val != 0 {
+        linux::activate_window(val as u32);
+    }
 }
 
+#[cfg(target_os = "linux")]
+const MODIFIER_RELEASE_TIMEOUT_MS: u64 = 300;
+
 fn simulate_copy(_enigo: &mut Enigo) {
     #[cfg(target_os = "macos")]
     unsafe { macos::simulate_copy_private_source() };
 
+    #[cfg(target_os = "linux")]
+    if linux::is_wayland() {
+        linux::copy_primary_selection();
+        return;
+    }
+
+    // macOS posts from a private event source, which ignores the keys still
+    // held from the shortcut. On X11, wait for the user to let go instead, and
+    // release the modifiers ourselves if they take too long.
+    #[cfg(target_os = "linux")]
+    if !linux::wait_for_modifiers_released(std::time::Duration::from_millis(MODIFIER_RELEASE_TIMEOUT_MS)) {
+        for key in [Key::Shift, Key::Alt, Key::Meta, Key::Control] {
+            let _ = _enigo.key(key, Direction::Release);
+        }
+    }
+
     #[cfg(not(target_os = "macos"))]
     {
         let _ = _enigo.key(Key::Control, Direction::Press);
@@ -205,251 +1038,2927 @@ fn clipboard_generation() -> u64 {
     #[cfg(target_os = "macos")]
     return unsafe { macos::pasteboard_change_count() } as u64;
 
-    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    #[cfg(target_os = "linux")]
+    return linux::clipboard_change_count();
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
     return 0;
 }
 
-fn simulate_paste(enigo: &mut Enigo) {
-    let modifier = if cfg!(target_os = "macos") { Key::Meta } else { Key::Control };
-    let _ = enigo.key(modifier, Direction::Press);
-    let _ = enigo.key(Key::Unicode('v'), Direction::Click);
-    let _ = enigo.key(modifier, Direction::Release);
+// Used when the simulated copy left the clipboard untouched. Some Linux apps
+// ignore synthetic Ctrl+C (terminals expect Ctrl+Shift+C), but their
+// highlighted text is still available as the PRIMARY selection.
+fn fallback_selection() -> String {
+    #[cfg(target_os = "linux")]
+    return linux::read_primary_selection().unwrap_or_default();
+
+    #[cfg(not(target_os = "linux"))]
+    return String::new();
 }
 
-// ---- Collections directory setup ----
+// Strips the single line terminator most commands end their output with,
+// whether "\n" or "\r\n". Any further blank lines are kept.
+fn trim_line_ending(output: &str) -> &str {
+    output.strip_suffix("\r\n")
+        .or_else(|| output.strip_suffix('\n'))
+        .unwrap_or(output)
+}
 
-fn ensure_collections_dir(dir: &Path) {
-    if dir.exists() { return; }
+// The text append/prepend paste puts over the selection. Without a
+// pasteSeparator, multi-line text is joined with a newline and single-line
+// text with a space. A selection that ended in a line ending keeps it at the
+// very end, so appending to whole lines doesn't glue onto the next one.
+fn combine_with_selection(mode: PasteMode, separator: Option<&str>, selection: &str, output: &str) -> String {
+    if selection.is_empty() {
+        return output.to_string();
+    }
+    let separator = separator.unwrap_or(if selection.contains('\n') || output.contains('\n') { "\n" } else { " " });
+    match mode {
+        PasteMode::Replace => output.to_string(),
+        PasteMode::Append => {
+            let body = trim_line_ending(selection);
+            format!("{}{}{}{}", body, separator, output, &selection[body.len()..])
+        }
+        PasteMode::Prepend => format!("{}{}{}", output, separator, selection),
+    }
+}
 
-    let _ = std::fs::create_dir_all(dir);
+// Enigo can't start without accessibility permission on macOS, or without an
+// X display on Linux (e.g. under Wayland). The error says so, for the palette
+// to show instead of a paste that silently never happens.
+fn input_simulator() -> Result<Enigo, SpellError> {
+    Enigo::new(&Settings::default())
+        .map_err(|e| SpellError::InputUnavailable { message: format!("input simulation unavailable: {}", e) })
+}
 
-    let hello_dir = dir.join("hello");
-    let _ = std::fs::create_dir(&hello_dir);
-    let _ = std::fs::write(
-        hello_dir.join("index.json"),
-        r#"{
-  "spells": [
+// Runs f with a fresh input simulator and waits for it. macOS only accepts
+// simulated input from the main thread, so there f is dispatched to it; spells
+// run on worker threads. Elsewhere f runs in place.
+fn with_input_simulator<R: Send + 'static>(
+    app: &AppHandle,
+    f: impl FnOnce(&mut Enigo) -> R + Send + 'static,
+) -> Result<R, SpellError> {
+    #[cfg(target_os = "macos")]
     {
-      "trigger": "hello",
-      "description": "Generate \"Hello, World!\"",
-      "entry": {
-        "default": "echo Hello, World!"
-      }
+        let (tx, rx) = std::sync::mpsc::channel();
+        app.run_on_main_thread(move || {
+            let _ = tx.send(input_simulator().map(|mut enigo| f(&mut enigo)));
+        })
+        .map_err(|e| SpellError::InputUnavailable { message: format!("input simulation unavailable: {}", e) })?;
+        rx.recv().map_err(|_| SpellError::InputUnavailable { message: "input simulation was interrupted".to_string() })?
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = app;
+        input_simulator().map(|mut enigo| f(&mut enigo))
     }
-  ]
-}
-"#,
-    );
 }
 
-// ---- Collection loading ----
-
-fn load_collections(dir: &Path) -> Vec<LoadedSpell> {
-    let mut spells = Vec::new();
-    let Ok(entries) = std::fs::read_dir(dir) else { return spells };
-    for entry in entries.flatten() {
-        let path = entry.path();
-        if !path.is_dir() { continue; }
-        let Ok(content) = std::fs::read_to_string(path.join("index.json")) else { continue };
-        let Ok(index) = serde_json::from_str::<CollectionIndex>(&content) else { continue };
-        for def in index.spells {
-            spells.push(LoadedSpell {
-                trigger: def.trigger,
-                description: def.description,
-                collection_dir: path.clone(),
-                entry_cmd: def.entry.default,
-                output_mode: def.settings.as_ref().map(|s| s.output_mode.clone()).unwrap_or_default(),
-                stream_mode: def.settings.map(|s| s.stream_mode).unwrap_or(false),
-            });
-        }
-    }
-    spells
+fn simulate_paste(enigo: &mut Enigo) {
+    let modifier = if cfg!(target_os = "macos") { Key::Meta } else { Key::Control };
+    let _ = enigo.key(modifier, Direction::Press);
+    let _ = enigo.key(Key::Unicode('v'), Direction::Click);
+    let _ = enigo.key(modifier, Direction::Release);
 }
 
-// ---- Spell execution ----
+// Types text as keystrokes. enigo.text drops or mangles characters around
+// embedded newlines on some platforms, so each line is typed on its own with
+// an explicit Return in between (see playground2). With a delay, graphemes go
+// one at a time so slow apps that drop fast input can keep up; a stream's
+// cancel flag stops it early.
+fn type_text(enigo: &mut Enigo, text: &str, delay_ms: Option<u64>, cancelled: Option<&AtomicBool>) {
+    use unicode_segmentation::UnicodeSegmentation;
 
-fn spawn_entry(entry_cmd: &str, collection_dir: &Path, input: &str) -> Result<std::process::Child, String> {
-    use std::io::Write;
-    use std::process::{Command, Stdio};
+    let is_cancelled = || cancelled.is_some_and(|c| c.load(Ordering::SeqCst));
+    let pause = || {
+        if let Some(ms) = delay_ms {
+            std::thread::sleep(std::time::Duration::from_millis(ms));
+        }
+    };
+    for (i, line) in text.split('\n').enumerate() {
+        if is_cancelled() { return; }
+        if i > 0 {
+            let _ = enigo.key(Key::Return, Direction::Click);
+            pause();
+        }
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if delay_ms.is_none() {
+            if !line.is_empty() {
+                let _ = enigo.text(line);
+            }
+            continue;
+        }
+        for grapheme in line.graphemes(true) {
+            if is_cancelled() { return; }
+            let _ = enigo.text(grapheme);
+            pause();
+        }
+    }
+}
 
-    #[cfg(target_os = "windows")]
-    let (shell, flag) = ("cmd", "/C");
-    #[cfg(not(target_os = "windows"))]
-    let (shell, flag) = ("sh", "-c");
+// Collapses the selection in the focused app to its end (Right) or start
+// (Left), leaving the caret there.
+fn collapse_selection(enigo: &mut Enigo, to_end: bool) {
+    let key = if to_end { Key::RightArrow } else { Key::LeftArrow };
+    let _ = enigo.key(key, Direction::Click);
+}
 
-    let mut child = Command::new(shell)
-        .arg(flag)
-        .arg(entry_cmd)
-        .current_dir(collection_dir)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::null())
-        .spawn()
-        .map_err(|e| e.to_string())?;
+// Clipboard contents saved so they can be put back: text, or an image when
+// the clipboard held no text.
+enum ClipboardSnapshot {
+    Text(String),
+    Image(arboard::ImageData<'static>),
+}
 
-    if let Some(mut stdin) = child.stdin.take() {
-        let _ = stdin.write_all(input.as_bytes());
+impl ClipboardSnapshot {
+    fn take(clipboard: &mut arboard::Clipboard) -> Option<ClipboardSnapshot> {
+        if let Ok(text) = clipboard.get_text() {
+            return Some(ClipboardSnapshot::Text(text));
+        }
+        clipboard.get_image().ok().map(ClipboardSnapshot::Image)
+    }
+
+    fn read() -> Option<ClipboardSnapshot> {
+        ClipboardSnapshot::take(&mut arboard::Clipboard::new().ok()?)
+    }
+
+    fn to_input(&self) -> SpellInput {
+        match self {
+            ClipboardSnapshot::Text(text) => SpellInput::Text(text.clone()),
+            ClipboardSnapshot::Image(image) => encode_png(image).map(SpellInput::Image).unwrap_or_default(),
+        }
+    }
+
+    fn restore(&self) {
+        if let Ok(mut clipboard) = arboard::Clipboard::new() {
+            let _ = match self {
+                ClipboardSnapshot::Text(text) => clipboard.set_text(text),
+                ClipboardSnapshot::Image(image) => clipboard.set_image(image.clone()),
+            };
+        }
     }
+}
 
-    Ok(child)
+// The target app reads the clipboard asynchronously after Ctrl+V, so give the
+// paste time to land before putting the previous contents back.
+fn restore_clipboard_later(snapshot: Arc<ClipboardSnapshot>, delay_ms: u64) {
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(delay_ms));
+        snapshot.restore();
+    });
 }
 
-fn execute_spell(entry_cmd: &str, collection_dir: &Path, input: &str) -> Result<String, String> {
-    let output = spawn_entry(entry_cmd, collection_dir, input)?
-        .wait_with_output()
-        .map_err(|e| e.to_string())?;
-    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+// ---- Config ----
+
+// A missing file gives the defaults. So does a corrupt one, but it is copied
+// to config.json.bak first: the next save would otherwise overwrite it.
+fn load_config(path: &Path) -> AppConfig {
+    let Ok(content) = std::fs::read_to_string(path) else { return AppConfig::default() };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        let backup = path.with_extension("json.bak");
+        log_warning(format!("{} is invalid ({e}), using defaults; kept a copy as {}", path.display(), backup.display()));
+        let _ = std::fs::copy(path, &backup);
+        AppConfig::default()
+    })
 }
 
-fn pipe_stdout_to_channel(stdout: std::process::ChildStdout) -> std::sync::mpsc::Receiver<String> {
-    use std::io::Read;
+fn current_config(app: &AppHandle) -> AppConfig {
+    app.try_state::<ConfigStore>()
+        .map(|config| config.0.lock().unwrap().clone())
+        .unwrap_or_default()
+}
 
-    let (tx, rx) = std::sync::mpsc::channel();
+fn save_config(path: &Path, config: &AppConfig) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let content = serde_json::to_string_pretty(config).map_err(|e| e.to_string())?;
+    std::fs::write(path, content).map_err(|e| e.to_string())
+}
+
+// ---- Usage stats ----
+
+fn load_stats(path: &Path) -> HashMap<String, SpellStats> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_stats(path: &Path, stats: &HashMap<String, SpellStats>) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let content = serde_json::to_string_pretty(stats).map_err(|e| e.to_string())?;
+    std::fs::write(path, content).map_err(|e| e.to_string())
+}
+
+// Writes happen on a background thread so a slow disk never delays a spell.
+// Each write takes the current stats while holding the write lock, so writes
+// never interleave and the last one to finish always has the newest stats.
+fn save_stats_in_background(app: &AppHandle) {
+    static WRITE_LOCK: Mutex<()> = Mutex::new(());
+    let app = app.clone();
     std::thread::spawn(move || {
-        let mut stdout = stdout;
-        let mut buf = [0u8; 4096];
-        loop {
-            match stdout.read(&mut buf) {
-                Ok(0) => break,
-                Ok(n) => {
-                    let s = String::from_utf8_lossy(&buf[..n]).into_owned();
-                    if tx.send(s).is_err() { break; }
-                }
-                Err(_) => break,
+        let _guard = WRITE_LOCK.lock().unwrap();
+        let Some(store) = app.try_state::<StatsStore>() else { return };
+        let stats = store.0.lock().unwrap().clone();
+        if let Err(e) = save_stats(&get_stats_path(), &stats) {
+            log_warning(format!("could not save stats ({e})"));
+        }
+    });
+}
+
+// Stats are kept for at most this many spells, and only while they keep
+// being used; triggers of deleted spells would otherwise pile up forever.
+const MAX_STATS_ENTRIES: usize = 500;
+const STATS_MAX_AGE_SECS: u64 = 180 * 24 * 60 * 60;
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Runs made through an alias are counted under the spell it stands in for.
+fn record_run(app: &AppHandle, spell: &LoadedSpell) {
+    let Some(store) = app.try_state::<StatsStore>() else { return };
+    let trigger = spell.primary_trigger.as_deref().unwrap_or(&spell.trigger);
+    let mut stats = store.0.lock().unwrap();
+    let now = unix_now();
+    let entry = stats.entry(trigger.to_string()).or_insert_with(|| SpellStats {
+        trigger: trigger.to_string(),
+        run_count: 0,
+        last_run_at: None,
+    });
+    entry.run_count += 1;
+    entry.last_run_at = Some(now);
+    prune_stats(&mut stats, now);
+    drop(stats);
+    save_stats_in_background(app);
+}
+
+// Drops entries not run within STATS_MAX_AGE_SECS, then the least recently
+// run ones beyond MAX_STATS_ENTRIES.
+fn prune_stats(stats: &mut HashMap<String, SpellStats>, now: u64) {
+    stats.retain(|_, s| s.last_run_at.is_some_and(|at| now.saturating_sub(at) <= STATS_MAX_AGE_SECS));
+    if stats.len() > MAX_STATS_ENTRIES {
+        let mut by_age: Vec<(Option<u64>, String)> = stats.values().map(|s| (s.last_run_at, s.trigger.clone())).collect();
+        by_age.sort();
+        for (_, trigger) in by_age.into_iter().take(stats.len() - MAX_STATS_ENTRIES) {
+            stats.remove(&trigger);
+        }
+    }
+}
+
+// Run count decayed by time since the last run, halving every week: a spell
+// used daily this week outranks one used a lot last year.
+fn frecency(stats: &SpellStats, now: u64) -> f64 {
+    let age_days = now.saturating_sub(stats.last_run_at.unwrap_or(0)) as f64 / 86_400.0;
+    stats.run_count as f64 * 0.5f64.powf(age_days / 7.0)
+}
+
+// ---- Favorites ----
+
+fn load_favorites(path: &Path) -> HashSet<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+// Stored as a sorted array so the file diffs cleanly.
+fn save_favorites(path: &Path, favorites: &HashSet<String>) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let mut triggers: Vec<&String> = favorites.iter().collect();
+    triggers.sort();
+    let content = serde_json::to_string_pretty(&triggers).map_err(|e| e.to_string())?;
+    std::fs::write(path, content).map_err(|e| e.to_string())
+}
+
+// ---- Collections directory setup ----
+
+fn ensure_collections_dir(dir: &Path) {
+    if dir.exists() { return; }
+
+    let _ = std::fs::create_dir_all(dir);
+
+    let hello_dir = dir.join("hello");
+    let _ = std::fs::create_dir(&hello_dir);
+    let _ = std::fs::write(
+        hello_dir.join("index.json"),
+        r#"{
+  "name": "Hello",
+  "description": "A starter collection",
+  "spells": [
+    {
+      "trigger": "hello",
+      "description": "Generate \"Hello, World!\"",
+      "entry": {
+        "default": "echo Hello, World!"
+      }
+    }
+  ]
+}
+"#,
+    );
+}
+
+// ---- Collection loading ----
+
+// Picks the command for the current platform, falling back to `default`.
+// Returns None when the spell has no command for this platform.
+fn resolve_entry_cmd(entry: &IndexEntry) -> Option<String> {
+    let platform_cmd = if cfg!(target_os = "windows") {
+        entry.windows.as_ref()
+    } else if cfg!(target_os = "macos") {
+        entry.macos.as_ref()
+    } else if cfg!(target_os = "linux") {
+        entry.linux.as_ref()
+    } else {
+        None
+    };
+    platform_cmd.or(entry.default.as_ref()).cloned()
+}
+
+// Index file names in order of preference; index.json wins so existing
+// collections keep loading the same way.
+const INDEX_FILES: &[&str] = &["index.json", "index.yaml", "index.yml", "index.toml"];
+
+fn is_index_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| INDEX_FILES.iter().any(|f| name == *f))
+}
+
+fn find_index_file(collection_dir: &Path) -> Option<PathBuf> {
+    let mut found = INDEX_FILES.iter()
+        .map(|name| collection_dir.join(name))
+        .filter(|path| path.is_file());
+    let index_path = found.next()?;
+    if let Some(ignored) = found.next() {
+        log_warning(format!(
+            "{} has both {} and {}, using the former",
+            collection_dir.display(),
+            index_path.file_name().unwrap_or_default().to_string_lossy(),
+            ignored.file_name().unwrap_or_default().to_string_lossy(),
+        ));
+    }
+    Some(index_path)
+}
+
+fn read_index_file(path: &Path) -> Result<(CollectionIndex, CollectionFormat), String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => serde_yaml::from_str(&content)
+            .map(|index| (index, CollectionFormat::Yaml))
+            .map_err(|e| e.to_string()),
+        Some("toml") => toml::from_str(&content)
+            .map(|index| (index, CollectionFormat::Toml))
+            .map_err(|e| e.to_string()),
+        _ => serde_json::from_str(&content)
+            .map(|index| (index, CollectionFormat::Json))
+            .map_err(|e| e.to_string()),
+    }
+}
+
+// Parses an index file. Errors name the file and keep the parser's line and
+// column, so a typo doesn't just make the collection vanish.
+fn validate_collection(index_path: &Path) -> Result<(CollectionIndex, CollectionFormat), String> {
+    let file_name = index_path.file_name().unwrap_or_default().to_string_lossy();
+    read_index_file(index_path).map_err(|e| format!("{}: {}", file_name, e))
+}
+
+// Loads every configured directory. When a trigger (aliases included) appears
+// in more than one collection, the first collection in load order keeps it and
+// the others are prefixed with trigger_prefix (e.g. "1:work/hello") so they
+// stay reachable; each clash is logged and reported as a diagnostic, and the
+// shadowing spell's description is marked [DUPLICATE]. In strict mode a clash
+// fails the whole load instead, with one error per clash.
+// Collections that fail to load are returned alongside instead of being
+// dropped silently.
+fn load_collections(sources: &[CollectionSource], strict_mode: bool) -> Result<LoadResult, Vec<(PathBuf, String)>> {
+    let started = std::time::Instant::now();
+    let mut errors = Vec::new();
+    let folders: Vec<PathBuf> = sources.iter()
+        .flat_map(|source| match source {
+            CollectionSource::Directory(dir) => collection_folders(dir, &mut errors),
+            // Not downloaded yet; the background refresh fetches it.
+            CollectionSource::Remote { cache_path, .. } if !cache_path.is_file() => Vec::new(),
+            CollectionSource::Remote { .. } => vec![source.root().to_path_buf()],
+        })
+        .collect();
+
+    // Index files are read in parallel, which helps on slow or network drives.
+    // Each worker keeps its own vectors; they are merged (and sorted) afterwards.
+    let workers = std::thread::available_parallelism().map_or(4, |n| n.get()).min(folders.len().max(1));
+    let chunk_size = folders.len().div_ceil(workers).max(1);
+    let mut collections = Vec::new();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = folders.chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || {
+                let mut errors = Vec::new();
+                let collections: Vec<_> = chunk.iter()
+                    .filter_map(|path| load_collection(path.clone(), &mut errors))
+                    .collect();
+                (collections, errors)
+            }))
+            .collect();
+        for handle in handles {
+            if let Ok((loaded, failed)) = handle.join() {
+                collections.extend(loaded);
+                errors.extend(failed);
             }
         }
     });
-    rx
+    // read_dir order varies between runs and platforms, so sort by (configured
+    // directory, order, folder path): earlier directories win trigger clashes
+    // whatever order a later one's collections ask for. The whole path keeps
+    // same-named nested folders (a/tools, b/tools) in a fixed order too.
+    collections.sort_by_cached_key(|c| {
+        let root = sources.iter().position(|s| c.dir.starts_with(s.root())).unwrap_or(usize::MAX);
+        (root, c.meta.order.unwrap_or(i64::MAX), c.dir.clone())
+    });
+
+    let mut first_seen: HashMap<String, PathBuf> = HashMap::new();
+    let mut diagnostics = Vec::new();
+    let mut duplicates = HashSet::new();
+    let all = collections.iter().enumerate()
+        .flat_map(|(c, collection)| collection.spells.iter().enumerate().map(move |(i, spell)| ((c, i), spell)));
+    for (position, spell) in all {
+        match first_seen.get(&spell.trigger) {
+            Some(first) => {
+                duplicates.insert(position);
+                log_warning(format!(
+                    "trigger '{}' is defined in both {} and {}",
+                    spell.trigger,
+                    first.display(),
+                    spell.collection_dir.display(),
+                ));
+                diagnostics.push(LoadDiagnostic {
+                    trigger: spell.trigger.clone(),
+                    first: first.clone(),
+                    second: spell.collection_dir.clone(),
+                });
+            }
+            None => { first_seen.insert(spell.trigger.clone(), spell.collection_dir.clone()); }
+        }
+    }
+    if strict_mode && !diagnostics.is_empty() {
+        errors.extend(diagnostics.into_iter().map(|d| {
+            let message = format!("trigger '{}' is already defined in {}", d.trigger, d.first.display());
+            (d.second, message)
+        }));
+        return Err(errors);
+    }
+    let shadowed = |trigger: &str, dir: &Path| first_seen.get(trigger).is_some_and(|first| first != dir);
+    for (c, collection) in collections.iter_mut().enumerate() {
+        for (i, spell) in collection.spells.iter_mut().enumerate() {
+            if duplicates.contains(&(c, i)) {
+                spell.description = Some(spell.description.as_ref().map_or("[DUPLICATE]".to_string(), |d| format!("[DUPLICATE] {}", d)));
+            }
+        }
+    }
+    for spell in collections.iter_mut().flat_map(|c| &mut c.spells) {
+        let prefix = trigger_prefix(&spell.collection_dir, sources);
+        if shadowed(&spell.trigger, &spell.collection_dir) {
+            spell.trigger = format!("{}/{}", prefix, spell.trigger);
+        }
+        if let Some(primary) = spell.primary_trigger.as_mut() {
+            if shadowed(primary, &spell.collection_dir) {
+                *primary = format!("{}/{}", prefix, primary);
+            }
+        }
+        // Kept in step with the alias entries, which were renamed above.
+        for alias in spell.aliases.iter_mut() {
+            if shadowed(alias, &spell.collection_dir) {
+                *alias = format!("{}/{}", prefix, alias);
+            }
+        }
+    }
+    let stats = LoadStats {
+        collections_loaded: collections.len(),
+        spells_loaded: collections.iter().map(|c| c.spells.len()).sum(),
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    };
+    Ok(LoadResult { collections, errors, diagnostics, stats })
+}
+
+// What a shadowed trigger is prefixed with: the position of its collections
+// directory and the collection's path inside it, e.g. "1:work/tools". Folder
+// names alone repeat across directories and nested groups.
+fn trigger_prefix(dir: &Path, sources: &[CollectionSource]) -> String {
+    let folder = dir.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let Some((root, relative)) = sources.iter().enumerate()
+        .find_map(|(i, source)| dir.strip_prefix(source.root()).ok().map(|relative| (i, relative)))
+    else {
+        return folder;
+    };
+    let relative: Vec<_> = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+    // A remote collection is its own root.
+    let relative = if relative.is_empty() { folder } else { relative.join("/") };
+    format!("{}:{}", root, relative)
+}
+
+// The index's "name", falling back to the folder name.
+fn collection_name(meta: &CollectionMeta, dir: &Path) -> String {
+    meta.name.clone()
+        .unwrap_or_else(|| dir.file_name().unwrap_or_default().to_string_lossy().to_string())
+}
+
+fn all_spells(collections: &[LoadedCollection]) -> Vec<LoadedSpell> {
+    collections.iter().flat_map(|c| c.spells.iter().cloned()).collect()
+}
+
+// Collection folders anywhere under a collections directory, so collections
+// can be grouped into plain folders (work/, personal/, ...). A folder with an
+// index file is a collection and is not searched further. Hidden folders are
+// skipped, and each folder is visited once by its canonical path so symlink
+// loops end.
+fn collection_folders(dir: &Path, errors: &mut Vec<(PathBuf, String)>) -> Vec<PathBuf> {
+    let mut folders = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect::<Vec<_>>(),
+        Err(e) => {
+            errors.push((dir.to_path_buf(), e.to_string()));
+            return folders;
+        }
+    };
+    while let Some(path) = pending.pop() {
+        let hidden = path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden || !path.is_dir() {
+            continue;
+        }
+        if !visited.insert(path.canonicalize().unwrap_or_else(|_| path.clone())) {
+            continue;
+        }
+        if INDEX_FILES.iter().any(|name| path.join(name).is_file()) {
+            folders.push(path);
+        } else if let Ok(entries) = std::fs::read_dir(&path) {
+            pending.extend(entries.flatten().map(|entry| entry.path()));
+        }
+    }
+    folders
+}
+
+fn load_collection(path: PathBuf, errors: &mut Vec<(PathBuf, String)>) -> Option<LoadedCollection> {
+    // A folder without an index file simply isn't a collection.
+    let index_path = find_index_file(&path)?;
+    let (index, collection_format) = match validate_collection(&index_path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            errors.push((path, e));
+            return None;
+        }
+    };
+    // Spells for other platforms are skipped quietly, but one with no command
+    // at all is almost always a misspelt "default".
+    for def in index.spells.iter().filter(|def| def.enabled != Some(false)) {
+        let entry = &def.entry;
+        let commands = [&entry.default, &entry.windows, &entry.macos, &entry.linux];
+        if commands.iter().all(|cmd| cmd.is_none()) && entry.interpreter.is_none() && entry.script.is_none() {
+            errors.push((path.clone(), format!("spell '{}' has no entry command", def.trigger)));
+        }
+    }
+    let name = collection_name(&index.meta, &path);
+    let mut defs: Vec<_> = index.spells.into_iter().enumerate().collect();
+    defs.sort_by(|(_, a), (_, b)| {
+        a.order.unwrap_or(i64::MAX).cmp(&b.order.unwrap_or(i64::MAX)).then_with(|| a.trigger.cmp(&b.trigger))
+    });
+    let mut spells = Vec::new();
+    let mut disabled = Vec::new();
+    // Triggers and aliases taken so far. Within one collection there is no
+    // prefix to tell a repeat apart, so it is reported instead of loaded.
+    let mut taken = HashSet::new();
+    for (index_position, def) in defs {
+        if def.enabled == Some(false) {
+            disabled.push(DisabledSpell { trigger: def.trigger, description: def.description, index_position });
+            continue;
+        }
+        let script = match (def.entry.interpreter.clone(), def.entry.script.clone()) {
+            (Some(interpreter), Some(script)) => Some(ScriptEntry { interpreter, script }),
+            (None, None) => None,
+            _ => {
+                errors.push((path.clone(), format!("spell '{}' needs both interpreter and script", def.trigger)));
+                continue;
+            }
+        };
+        let entry_cmd = match resolve_entry_cmd(&def.entry) {
+            Some(entry_cmd) => entry_cmd,
+            None if script.is_some() => String::new(),
+            None => continue,
+        };
+        let params = def.params.unwrap_or_default();
+        if let Some(param) = params.iter().find(|p| !is_param_name(&p.name)) {
+            errors.push((path.clone(), format!("spell '{}' has an invalid param name '{}'", def.trigger, param.name)));
+            continue;
+        }
+        let shell = def.settings.as_ref().and_then(|s| s.shell.clone()).unwrap_or_else(Shell::platform_default);
+        // A custom interpreter's quoting is unknown, so the selection can't be
+        // spliced into its source safely; it reads SPELLPASTE_INPUT or stdin instead.
+        let hooks = [def.before.as_deref(), def.after.as_deref()];
+        if matches!(shell, Shell::Custom(_))
+            && std::iter::once(entry_cmd.as_str()).chain(hooks.into_iter().flatten()).any(|cmd| cmd.contains(INPUT_PLACEHOLDER))
+        {
+            errors.push((path.clone(), format!(
+                "spell '{}' uses {} with the custom shell '{}'; read SPELLPASTE_INPUT or stdin instead",
+                def.trigger, INPUT_PLACEHOLDER, shell.program(),
+            )));
+            continue;
+        }
+        let mut aliases = def.aliases.unwrap_or_default();
+        let icon = def.icon.filter(|icon| !icon.trim().is_empty());
+        if let Some(icon) = icon.as_deref().filter(|icon| icon.contains(['/', '\\'])) {
+            if !path.join(icon).is_file() {
+                log_warning(format!("icon {} of spell '{}' not found in {}", icon, def.trigger, path.display()));
+            }
+        }
+        if !taken.insert(def.trigger.clone()) {
+            errors.push((path.clone(), format!("trigger '{}' is used more than once; only the first spell is loaded", def.trigger)));
+            continue;
+        }
+        aliases.retain(|alias| {
+            let fresh = taken.insert(alias.clone());
+            if !fresh {
+                errors.push((path.clone(), format!("alias '{}' of spell '{}' is already used and was skipped", alias, def.trigger)));
+            }
+            fresh
+        });
+        let spell = LoadedSpell {
+            trigger: def.trigger,
+            primary_trigger: None,
+            aliases: aliases.clone(),
+            description: def.description,
+            category: def.category.filter(|c| !c.trim().is_empty()),
+            icon,
+            collection_name: name.clone(),
+            collection_dir: path.clone(),
+            entry_cmd,
+            script,
+            env: def.entry.env.into_iter()
+                .chain(def.settings.iter().flat_map(|s| s.env.clone()))
+                .collect(),
+            args: def.entry.args,
+            output_mode: def.settings.as_ref().map(|s| s.output_mode.clone()).unwrap_or_default(),
+            stream_mode: def.settings.as_ref().map(|s| s.stream_mode).unwrap_or(false),
+            confirm: def.settings.as_ref().map(|s| s.confirm).unwrap_or(false),
+            preview_format: def.settings.as_ref().map(|s| s.preview_format).unwrap_or_default(),
+            paste_mode: def.settings.as_ref().map(|s| s.paste_mode).unwrap_or_default(),
+            paste_separator: def.settings.as_ref().and_then(|s| s.paste_separator.clone()),
+            append_separator: def.settings.as_ref().and_then(|s| s.append_separator.clone()),
+            input_from: def.settings.as_ref().map(|s| s.input_from).unwrap_or_default(),
+            preserve_clipboard: def.settings.as_ref().and_then(|s| s.preserve_clipboard),
+            stream_flush_ms: def.settings.as_ref().and_then(|s| s.stream_flush_ms),
+            type_delay_ms: def.settings.as_ref().and_then(|s| s.type_delay_ms).filter(|&ms| ms > 0),
+            shell,
+            shell_args: def.settings.as_ref().and_then(|s| s.shell_args.clone()).unwrap_or_default(),
+            output_path: def.settings.as_ref().and_then(|s| s.output_path.clone()),
+            output_append: def.settings.as_ref().is_some_and(|s| s.output_append),
+            index_position,
+            priority: def.priority.unwrap_or(0),
+            params,
+            param_values: BTreeMap::new(),
+            before_hook: def.before.filter(|cmd| !cmd.trim().is_empty()),
+            after_hook: def.after.filter(|cmd| !cmd.trim().is_empty()),
+            abort_on_hook_failure: def.abort_on_hook_failure,
+            // A zero timeout is treated as "no timeout" rather than killing the spell at once.
+            timeout_ms: def.settings
+                .and_then(|s| s.timeout_ms.or(s.timeout_secs.map(|secs| secs.saturating_mul(1000))))
+                .filter(|&ms| ms > 0),
+        };
+        let alias_spells: Vec<_> = aliases.into_iter()
+            .map(|alias| LoadedSpell {
+                trigger: alias,
+                primary_trigger: Some(spell.trigger.clone()),
+                ..spell.clone()
+            })
+            .collect();
+        spells.push(spell);
+        spells.extend(alias_spells);
+    }
+    Some(LoadedCollection { dir: path, meta: index.meta, collection_format, spells, disabled })
+}
+
+// The spell-load-warning payload: one line per duplicate trigger.
+fn load_warnings(diagnostics: &[LoadDiagnostic]) -> Vec<String> {
+    diagnostics.iter()
+        .map(|d| format!("'{}' in {} and {}", d.trigger, d.first.display(), d.second.display()))
+        .collect()
+}
+
+// Reloads every collection into SpellStore and tells the frontend to re-fetch the list.
+fn reload_spells(app: &AppHandle) {
+    if let (Some(store), Some(collection_store), Some(load_errors), Some(dirs)) = (
+        app.try_state::<SpellStore>(),
+        app.try_state::<CollectionStore>(),
+        app.try_state::<LoadErrors>(),
+        app.try_state::<CollectionsDirs>(),
+    ) {
+        let dirs = dirs.0.lock().unwrap().clone();
+        let config = current_config(app);
+        // A strict-mode clash keeps the spells from the last good load.
+        let loaded = match load_collections(&collection_sources(&dirs, &config.remote_collections), config.strict_mode) {
+            Ok(loaded) => loaded,
+            Err(errors) => {
+                *load_errors.0.lock().unwrap() = errors;
+                if let Some(state) = app.try_state::<LoadDiagnostics>() {
+                    state.0.lock().unwrap().clear();
+                }
+                let _ = app.emit("spells-refreshed", ());
+                return;
+            }
+        };
+        if !loaded.diagnostics.is_empty() {
+            let _ = app.emit("spell-load-warning", load_warnings(&loaded.diagnostics));
+        }
+        *store.0.lock().unwrap() = all_spells(&loaded.collections);
+        *collection_store.0.lock().unwrap() = loaded.collections;
+        *load_errors.0.lock().unwrap() = loaded.errors;
+        if let Some(state) = app.try_state::<LoadDiagnostics>() {
+            *state.0.lock().unwrap() = loaded.diagnostics;
+        }
+        if let Some(state) = app.try_state::<LastLoadStats>() {
+            *state.0.lock().unwrap() = loaded.stats;
+        }
+        let _ = app.emit("spells-refreshed", ());
+    }
+}
+
+// ---- Collection watcher ----
+
+// Any change to an index file counts. So does a collection folder that is
+// added, removed or renamed as a whole. Copying, moving or deleting a folder
+// may only be reported for the folder itself, not the index inside it.
+fn is_index_event(event: &notify::Event, dirs: &[PathBuf]) -> bool {
+    use notify::event::ModifyKind;
+    use notify::EventKind;
+
+    match event.kind {
+        EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_)) => event.paths.iter().any(|p| {
+            is_index_file(p)
+                || p.parent().is_some_and(|parent| dirs.iter().any(|dir| dir == parent))
+        }),
+        EventKind::Modify(_) => event.paths.iter().any(|p| is_index_file(p)),
+        _ => false,
+    }
+}
+
+type WatchEvents = std::sync::mpsc::Receiver<notify::Result<notify::Event>>;
+
+// Created before the app state is managed, so a directory added at any point
+// finds the watcher in WatcherHandle. Nothing is watched until watch_dir is
+// called for each directory.
+fn collections_watcher() -> notify::Result<(notify::RecommendedWatcher, WatchEvents)> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    Ok((notify::recommended_watcher(tx)?, rx))
 }
 
-// Calls on_flush every 200ms with the text accumulated so far.
-// on_flush(chunk, is_final): is_final=true on the last call (process done).
-fn stream_batched(rx: std::sync::mpsc::Receiver<String>, mut on_flush: impl FnMut(&str, bool)) {
+// Reloads spells whenever an index file changes. Editors often emit
+// several events per save, so a reload only runs once events have been quiet
+// for 300ms. Returns once the watcher is dropped and the channel closes.
+fn run_collections_watcher(app: &AppHandle, rx: WatchEvents) {
     use std::sync::mpsc;
-    use std::time::{Duration, Instant};
+    use std::time::Duration;
 
-    let flush_interval = Duration::from_millis(200);
-    let mut buf = String::new();
-    loop {
-        let deadline = Instant::now() + flush_interval;
+    let debounce = Duration::from_millis(300);
+    while let Ok(event) = rx.recv() {
+        let dirs = match app.try_state::<CollectionsDirs>() {
+            Some(dirs) => dirs.0.lock().unwrap().clone(),
+            None => continue,
+        };
+        if !event.is_ok_and(|e| is_index_event(&e, &dirs)) { continue; }
         loop {
-            let remaining = deadline.saturating_duration_since(Instant::now());
-            if remaining.is_zero() { break; }
-            match rx.recv_timeout(remaining) {
-                Ok(chunk) => buf.push_str(&chunk),
+            match rx.recv_timeout(debounce) {
+                Ok(_) => continue,
                 Err(mpsc::RecvTimeoutError::Timeout) => break,
-                Err(mpsc::RecvTimeoutError::Disconnected) => {
-                    on_flush(&buf, true);
-                    return;
-                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
             }
         }
-        if !buf.is_empty() {
-            on_flush(&buf, false);
-            buf.clear();
+        reload_spells(app);
+    }
+}
+
+// A directory that can't be watched (e.g. it doesn't exist yet) still loads;
+// it just needs a manual refresh.
+fn watch_dir(watcher: &mut notify::RecommendedWatcher, dir: &Path) {
+    use notify::{RecursiveMode, Watcher};
+
+    if let Err(e) = watcher.watch(dir, RecursiveMode::Recursive) {
+        log_warning(format!("could not watch {} ({e}), use Refresh Spells instead", dir.display()));
+    }
+}
+
+// ---- Remote collections ----
+
+// Local directories first, so their triggers win over remote ones.
+fn collection_sources(dirs: &[PathBuf], remote_urls: &[String]) -> Vec<CollectionSource> {
+    dirs.iter()
+        .cloned()
+        .map(CollectionSource::Directory)
+        .chain(remote_urls.iter().map(|url| CollectionSource::Remote {
+            url: url.clone(),
+            cache_path: remote_cache_path(url),
+        }))
+        .collect()
+}
+
+// ~/.spellpaste/cache/<first 16 hex digits of the URL's SHA-256>/index.json
+fn remote_cache_path(url: &str) -> PathBuf {
+    use sha2::{Digest, Sha256};
+
+    let hash: String = Sha256::digest(url.as_bytes())[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    get_app_dir().join("cache").join(hash).join("index.json")
+}
+
+// Downloads an index.json and checks that it parses. Remote spells run their
+// commands like local ones, so only https is accepted.
+fn fetch_remote_index(url: &str) -> Result<String, String> {
+    if !url.starts_with("https://") {
+        return Err(format!("'{}' is not an https:// URL", url));
+    }
+    let body = ureq::get(url)
+        .timeout(std::time::Duration::from_secs(15))
+        .call()
+        .map_err(|e| format!("{}: {}", url, e))?
+        .into_string()
+        .map_err(|e| format!("{}: {}", url, e))?;
+    serde_json::from_str::<CollectionIndex>(&body)
+        .map_err(|e| format!("{}: not a collection index ({})", url, e))?;
+    Ok(body)
+}
+
+// Returns whether the cached copy changed.
+fn write_remote_cache(cache_path: &Path, body: &str) -> Result<bool, String> {
+    if std::fs::read_to_string(cache_path).is_ok_and(|cached| cached == body) {
+        return Ok(false);
+    }
+    if let Some(parent) = cache_path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(cache_path, body).map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+// Downloads every remote collection again and reloads spells if any of them
+// changed. A failed download keeps the cached copy and is only logged.
+fn refresh_remote_collections(app: &AppHandle) {
+    let mut changed = false;
+    for source in collection_sources(&[], &current_config(app).remote_collections) {
+        let CollectionSource::Remote { url, cache_path } = source else { continue };
+        match fetch_remote_index(&url).and_then(|body| write_remote_cache(&cache_path, &body)) {
+            Ok(updated) => changed |= updated,
+            Err(e) => log_warning(format!("could not refresh remote collection ({e})")),
         }
     }
+    if changed {
+        reload_spells(app);
+    }
 }
 
+// ---- Collection archives ----
 
-fn start_spell_preview_stream(entry_cmd: String, collection_dir: PathBuf, input: String, app: AppHandle) {
-    std::thread::spawn(move || {
-        let Ok(mut child) = spawn_entry(&entry_cmd, &collection_dir, &input) else {
-            let _ = app.emit("spell-stream-end", ());
-            return;
-        };
-        let rx = pipe_stdout_to_channel(child.stdout.take().unwrap());
-        stream_batched(rx, |chunk, is_final| {
-            if !chunk.is_empty() {
-                let _ = app.emit("spell-stream", chunk);
+// Folders left out of exported archives.
+const EXPORT_SKIP_DIRS: &[&str] = &[".git", "node_modules"];
+
+// Adds every file under dir to the archive, named by its path relative to root
+// with / separators.
+fn add_dir_to_zip(zip: &mut zip::ZipWriter<std::fs::File>, root: &Path, dir: &Path) -> Result<(), String> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            if !path.file_name().is_some_and(|name| EXPORT_SKIP_DIRS.iter().any(|skip| name == *skip)) {
+                add_dir_to_zip(zip, root, &path)?;
             }
-            if is_final {
-                let _ = app.emit("spell-stream-end", ());
+            continue;
+        }
+        let name = path.strip_prefix(root).unwrap_or(&path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        // Keeps scripts executable after a round trip.
+        #[cfg(unix)]
+        let options = {
+            use std::os::unix::fs::PermissionsExt;
+            match path.metadata() {
+                Ok(metadata) => options.unix_permissions(metadata.permissions().mode()),
+                Err(_) => options,
             }
-        });
-        let _ = child.wait();
-    });
+        };
+        zip.start_file(name, options).map_err(|e| e.to_string())?;
+        let mut file = std::fs::File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        std::io::copy(&mut file, zip).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+// Entry names that stay inside the extraction folder. Any entry that would
+// escape it (absolute, or with a .. component) fails the whole import rather
+// than being skipped.
+fn archive_entry_names(archive: &mut zip::ZipArchive<std::fs::File>) -> Result<Vec<PathBuf>, String> {
+    (0..archive.len())
+        .map(|i| {
+            let entry = archive.by_index(i).map_err(|e| e.to_string())?;
+            let has_parent_dir = Path::new(entry.name())
+                .components()
+                .any(|c| c == std::path::Component::ParentDir);
+            entry.enclosed_name()
+                .filter(|_| !has_parent_dir)
+                .ok_or_else(|| format!("archive entry '{}' points outside the collection", entry.name()))
+        })
+        .collect()
+}
+
+// Where the collection sits in an archive: at the root (as export_collection
+// writes it), or inside a single top-level folder (as when a folder is zipped
+// from a file manager). macOS resource forks are ignored.
+fn archive_root(names: &[PathBuf]) -> Option<PathBuf> {
+    let names: Vec<&PathBuf> = names.iter().filter(|n| !n.starts_with("__MACOSX")).collect();
+    let has_index = |dir: &Path| names.iter().any(|n| n.parent() == Some(dir) && is_index_file(n));
+    if has_index(Path::new("")) {
+        return Some(PathBuf::new());
+    }
+    let top = PathBuf::from(names.first()?.components().next()?.as_os_str());
+    (names.iter().all(|n| n.starts_with(&top)) && has_index(&top)).then_some(top)
+}
+
+fn extract_archive(
+    archive: &mut zip::ZipArchive<std::fs::File>,
+    names: &[PathBuf],
+    root: &Path,
+    target: &Path,
+) -> Result<(), String> {
+    for (i, name) in names.iter().enumerate() {
+        let Ok(relative) = name.strip_prefix(root) else { continue };
+        if relative.as_os_str().is_empty() || relative.starts_with("__MACOSX") {
+            continue;
+        }
+        let mut entry = archive.by_index(i).map_err(|e| e.to_string())?;
+        let out = target.join(relative);
+        if entry.is_dir() {
+            std::fs::create_dir_all(&out).map_err(|e| e.to_string())?;
+            continue;
+        }
+        if let Some(parent) = out.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let mut file = std::fs::File::create(&out).map_err(|e| format!("{}: {}", out.display(), e))?;
+        std::io::copy(&mut entry, &mut file).map_err(|e| format!("{}: {}", out.display(), e))?;
+        #[cfg(unix)]
+        if let Some(mode) = entry.unix_mode() {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = std::fs::set_permissions(&out, std::fs::Permissions::from_mode(mode & 0o777));
+        }
+    }
+    Ok(())
+}
+
+// ---- Spell search ----
+
+// Case-insensitive subsequence match. Returns None unless every query character
+// appears in order. Consecutive matches and matches at word starts score higher,
+// gaps between matches score lower.
+fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut start = 0;
+    let mut prev: Option<usize> = None;
+    for qc in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let pos = start + text[start..].iter().position(|&c| c == qc)?;
+        score += 1;
+        if pos == 0 || !text[pos - 1].is_alphanumeric() {
+            score += 3;
+        }
+        match prev {
+            Some(p) if p + 1 == pos => score += 5,
+            Some(p) => score -= (pos - p - 1).min(5) as i64,
+            None => score -= pos.min(5) as i64,
+        }
+        prev = Some(pos);
+        start = pos + 1;
+    }
+    Some(score)
 }
 
-fn start_spell_type_stream(entry_cmd: String, collection_dir: PathBuf, input: String) {
-    std::thread::spawn(move || {
-        let Ok(mut enigo) = Enigo::new(&Settings::default()) else { return };
-        let Ok(mut child) = spawn_entry(&entry_cmd, &collection_dir, &input) else { return };
-        let rx = pipe_stdout_to_channel(child.stdout.take().unwrap());
-        stream_batched(rx, |chunk, _is_final| {
-            if !chunk.is_empty() {
-                let _ = enigo.text(chunk);
-            }
-        });
-        let _ = child.wait();
-    });
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Catches typos the subsequence match misses ("uppr" or "upoer" for "upper")
+// by comparing the query with the start of the trigger, allowing the prefix to
+// be a little shorter or longer than the query. Always ranks below real matches.
+fn typo_score(query: &str, trigger: &str) -> Option<i64> {
+    let query: Vec<char> = query.to_lowercase().chars().filter(|c| !c.is_whitespace()).collect();
+    if query.len() < 3 { return None; }
+    let trigger: Vec<char> = trigger.to_lowercase().chars().collect();
+    let allowed = if query.len() >= 8 { 2 } else { 1 };
+    let distance = (query.len() - allowed..=query.len() + allowed)
+        .map(|len| levenshtein(&query, &trigger[..len.min(trigger.len())]))
+        .min()?;
+    (distance <= allowed).then(|| -100 - 10 * distance as i64)
+}
+
+// Description matches count for half as much as trigger matches.
+fn spell_score(query: &str, spell: &LoadedSpell) -> Option<i64> {
+    let trigger = fuzzy_score(query, &spell.trigger);
+    let description = spell.description.as_deref()
+        .and_then(|d| fuzzy_score(query, d))
+        .map(|score| score / 2);
+    trigger.max(description).or_else(|| typo_score(query, &spell.trigger))
+}
+
+// Matching spells, best first; ties go to the shorter trigger. A blank query
+// matches every spell and keeps the loaded order.
+fn rank_spells<'a>(query: &str, spells: &'a [LoadedSpell]) -> Vec<&'a LoadedSpell> {
+    if query.trim().is_empty() {
+        return spells.iter().collect();
+    }
+    let mut matches: Vec<(i64, &LoadedSpell)> = spells.iter()
+        .filter_map(|s| spell_score(query, s).map(|score| (score, s)))
+        .collect();
+    matches.sort_by(|(a_score, a), (b_score, b)| {
+        b_score.cmp(a_score).then(a.trigger.len().cmp(&b.trigger.len()))
+    });
+    matches.into_iter().map(|(_, s)| s).collect()
+}
+
+// ---- Spell execution ----
+
+// Expands ${VAR} from the app's own environment (unset variables become empty),
+// so a spell can write "PATH": "${PATH}:/opt/tools/bin". The resulting variables
+// are set on top of the inherited environment rather than replacing it.
+fn expand_env_vars(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else { break };
+        out.push_str(&rest[..start]);
+        out.push_str(&std::env::var(&rest[start + 2..start + 2 + len]).unwrap_or_default());
+        rest = &rest[start + 2 + len + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+const INPUT_PLACEHOLDER: &str = "{{input}}";
+
+// What {{input}} becomes: the selected text as a single quoted shell word.
+// sh gets a single-quoted literal. cmd and PowerShell have no escaping that is
+// reliable for arbitrary text, so there the text travels in SPELLPASTE_INPUT
+// and is read back after the command line has been parsed: via delayed
+// expansion (cmd /V:ON) or $env:. A custom interpreter's syntax is unknown, so
+// {{input}} is refused for it at load time and the text is never spliced in;
+// it only gets SPELLPASTE_INPUT.
+fn input_replacement(shell: &Shell, input: &str) -> String {
+    match shell {
+        Shell::Cmd => "\"!SPELLPASTE_INPUT!\"".to_string(),
+        Shell::Pwsh | Shell::Powershell => "$env:SPELLPASTE_INPUT".to_string(),
+        Shell::Sh => sh_quote(input),
+        Shell::Custom(_) => INPUT_PLACEHOLDER.to_string(),
+    }
+}
+
+// A single-quoted sh word holding `text` literally.
+fn sh_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+// Variables a param must not replace: they change how the spell's process
+// (or the loader starting it) behaves, not just what it reads.
+const RESERVED_ENV_NAMES: &[&str] = &[
+    "PATH", "PATHEXT", "HOME", "USER", "USERPROFILE", "SHELL", "IFS", "ENV", "BASH_ENV", "CDPATH",
+    "PWD", "TMPDIR", "TEMP", "TMP", "COMSPEC", "SYSTEMROOT", "WINDIR", "PSMODULEPATH",
+];
+const RESERVED_ENV_PREFIXES: &[&str] = &["LD_", "DYLD_", "SPELLPASTE_"];
+
+// Param names double as environment variables, so they are kept to
+// identifier characters and may not shadow the reserved variables above.
+fn is_param_name(name: &str) -> bool {
+    let upper = name.to_ascii_uppercase();
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !RESERVED_ENV_NAMES.contains(&upper.as_str())
+        && !RESERVED_ENV_PREFIXES.iter().any(|prefix| upper.starts_with(prefix))
+}
+
+// Rewrites $NAME for each param so its value is never parsed as code: sh gets
+// the value as a single-quoted word (so write $NAME outside double quotes, or
+// "${NAME}" to read the variable inside them), cmd gets !NAME! (delayed
+// expansion) and PowerShell $env:NAME. A custom interpreter's syntax is
+// unknown, so its command is left alone and it reads the environment
+// variables itself. Other $words are left alone.
+fn substitute_params(shell: &Shell, entry_cmd: &str, values: &BTreeMap<String, String>) -> String {
+    if values.is_empty() || matches!(shell, Shell::Custom(_)) {
+        return entry_cmd.to_string();
+    }
+    let mut out = String::with_capacity(entry_cmd.len());
+    let mut rest = entry_cmd;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let len = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+        let name = &after[..len];
+        match values.get(name) {
+            Some(value) => out.push_str(&match shell {
+                Shell::Cmd => format!("!{}!", name),
+                Shell::Pwsh | Shell::Powershell => format!("$env:{}", name),
+                Shell::Sh => sh_quote(value),
+                Shell::Custom(_) => unreachable!("custom shells return early"),
+            }),
+            None => {
+                out.push('$');
+                out.push_str(name);
+            }
+        }
+        rest = &after[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Pairs each param with the user's value, falling back to its default.
+// Names were checked with is_param_name at load time; the check is repeated
+// here because these become the spell's environment.
+fn resolve_params(spell: &LoadedSpell, provided: &HashMap<String, String>) -> Result<BTreeMap<String, String>, String> {
+    spell.params.iter()
+        .map(|param| {
+            if !is_param_name(&param.name) {
+                return Err(format!("Spell '{}' has an invalid param name '{}'", spell.trigger, param.name));
+            }
+            provided.get(&param.name).or(param.default.as_ref())
+                .map(|value| (param.name.clone(), value.clone()))
+                .ok_or_else(|| format!("Spell '{}' needs a value for {}", spell.trigger, param.name))
+        })
+        .collect()
+}
+
+// Resolves an interpreter to the file that will be run: a path is taken
+// relative to the collection, a bare name is looked up on PATH (with PATHEXT
+// on Windows).
+fn find_program(program: &str, collection_dir: &Path) -> Option<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        let path = collection_dir.join(path);
+        return path.is_file().then_some(path);
+    }
+    let search_path = std::env::var_os("PATH")?;
+    let extensions: Vec<String> = if cfg!(target_os = "windows") {
+        std::env::var("PATHEXT")
+            .unwrap_or_else(|_| ".EXE;.CMD;.BAT".to_string())
+            .split(';')
+            .map(str::to_string)
+            .chain(std::iter::once(String::new()))
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+    std::env::split_paths(&search_path).find_map(|dir| {
+        extensions.iter()
+            .map(|ext| dir.join(format!("{}{}", program, ext)))
+            .find(|candidate| candidate.is_file())
+    })
+}
+
+// Wraps the entry command in the spell's shell. Also reports whether the
+// command used {{input}}, in which case stdin is not needed for text.
+fn shell_command(spell: &LoadedSpell, input: &str) -> Result<(std::process::Command, bool), String> {
+    use std::process::Command;
+
+    let uses_placeholder = spell.entry_cmd.contains(INPUT_PLACEHOLDER);
+    // Params are replaced in the spell's own text only, piece by piece around
+    // {{input}}: a param value holding {{input}}, or a selection holding $NAME,
+    // is never expanded a second time.
+    let entry_cmd = spell.entry_cmd.split(INPUT_PLACEHOLDER)
+        .map(|piece| substitute_params(&spell.shell, piece, &spell.param_values))
+        .collect::<Vec<_>>()
+        .join(&input_replacement(&spell.shell, input));
+
+    let program = spell.shell.program();
+    let mut command = match &spell.shell {
+        Shell::Custom(_) => Command::new(
+            find_program(program, &spell.collection_dir)
+                .ok_or_else(|| format!("shell '{}' was not found on PATH", program))?,
+        ),
+        _ => Command::new(program),
+    };
+    command.args(&spell.shell_args);
+    match &spell.shell {
+        Shell::Cmd => {
+            if uses_placeholder || !spell.param_values.is_empty() {
+                command.arg("/V:ON");
+            }
+            command.arg("/C").arg(&entry_cmd);
+        }
+        Shell::Pwsh | Shell::Powershell => {
+            command.args(["-NoProfile", "-NonInteractive", "-Command"]).arg(&entry_cmd);
+        }
+        Shell::Sh => {
+            // sh -c assigns the first argument after the script to $0.
+            command.arg("-c").arg(&entry_cmd).arg("sh");
+        }
+        Shell::Custom(_) => {
+            command.arg(&entry_cmd);
+        }
+    }
+    if uses_placeholder && spell.shell != Shell::Sh {
+        command.env("SPELLPASTE_INPUT", input);
+    }
+    Ok((command, uses_placeholder))
+}
+
+// Runs the interpreter on the script directly, with no shell in between.
+fn script_command(spell: &LoadedSpell, entry: &ScriptEntry) -> Result<std::process::Command, String> {
+    let interpreter = find_program(&entry.interpreter, &spell.collection_dir)
+        .ok_or_else(|| format!("interpreter '{}' was not found on PATH", entry.interpreter))?;
+    let mut command = std::process::Command::new(interpreter);
+    command.arg(spell.collection_dir.join(&entry.script));
+    Ok(command)
+}
+
+// A file that is deleted when dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// Writes `bytes` to a new file in the temp directory that only we can read.
+// The name is unpredictable and the file must not exist yet (create_new), so
+// a file or symlink planted in a shared /tmp is never written through, and
+// concurrent spells each get their own.
+fn create_temp_file(extension: &str, bytes: &[u8]) -> std::io::Result<TempFile> {
+    use std::hash::{BuildHasher, Hasher};
+    use std::io::Write;
+
+    let mut attempts = 0;
+    loop {
+        // RandomState is keyed randomly, which makes the name hard to guess.
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        hasher.write_u128(std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos()));
+        let path = std::env::temp_dir().join(format!("spellpaste-{:016x}.{}", hasher.finish(), extension));
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        match options.open(&path) {
+            Ok(mut file) => {
+                let temp = TempFile(path);
+                file.write_all(bytes)?;
+                return Ok(temp);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempts < 8 => attempts += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Spell args are passed after the shell command (or script), so an sh entry
+// sees them as $1, $2, ... while cmd and PowerShell append them to the command
+// line. When the command uses {{input}}, the selection is substituted there
+// instead of being written to stdin.
+//
+// The spell's before hook runs to completion first; see run_before_hook. An
+// image input's file comes back with the child; keep it until the child has
+// exited, dropping it deletes the file.
+fn spawn_entry(
+    spell: &LoadedSpell,
+    input: &SpellInput,
+    capture_stderr: bool,
+    app: Option<&AppHandle>,
+) -> Result<(std::process::Child, Option<TempFile>), SpellError> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    run_before_hook(spell, input, app)?;
+    let (mut command, program, uses_placeholder) = match &spell.script {
+        Some(entry) => (script_command(spell, entry)?, entry.interpreter.as_str(), false),
+        None => {
+            let (command, uses_placeholder) = shell_command(spell, input.text())?;
+            (command, spell.shell.program(), uses_placeholder)
+        }
+    };
+    // An image can't go into {{input}}, so it is always sent on stdin. It is
+    // also saved to a file named in SPELLPASTE_IMAGE, for tools that want a path.
+    let pipe_stdin = !uses_placeholder || matches!(input, SpellInput::Image(_));
+    let mut image_file = None;
+    if let SpellInput::Image(png) = input {
+        match create_temp_file("png", png) {
+            Ok(file) => {
+                command.env("SPELLPASTE_IMAGE", &file.0);
+                image_file = Some(file);
+            }
+            Err(e) => log_warning(format!("could not save the image input ({e})")),
+        }
+    }
+
+    let mut child = command
+        .args(&spell.args)
+        .env("SPELLPASTE_INPUT_KIND", input.kind())
+        .envs(spell.env.iter().map(|(name, value)| (name, expand_env_vars(value))))
+        .envs(&spell.param_values)
+        .current_dir(&spell.collection_dir)
+        .stdin(if pipe_stdin { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(if capture_stderr { Stdio::piped() } else { Stdio::null() })
+        .spawn()
+        .map_err(|e| SpellError::IoError { message: format!("could not start {}: {}", program, e) })?;
+
+    // Feed stdin from its own thread: a spell that writes more than a pipe
+    // buffer before it finishes reading would otherwise deadlock against us.
+    // The pipe is dropped when the write ends, which signals EOF.
+    if let Some(mut stdin) = child.stdin.take() {
+        let input = input.bytes().to_vec();
+        std::thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+
+    Ok((child, image_file))
+}
+
+// Runs a before or after hook through the spell's shell, in its folder and
+// with the same input on stdin, and waits for it.
+fn run_hook(spell: &LoadedSpell, which: &str, cmd: &str, input: &SpellInput) -> Result<(), String> {
+    let hook = LoadedSpell {
+        entry_cmd: cmd.to_string(),
+        script: None,
+        args: Vec::new(),
+        before_hook: None,
+        after_hook: None,
+        ..spell.clone()
+    };
+    let failed = |e: &dyn std::fmt::Display| format!("{} hook of '{}': {}", which, spell.trigger, e);
+    let (mut child, _image) = spawn_entry(&hook, input, true, None).map_err(|e| failed(&e))?;
+    let stdout = read_in_background(child.stdout.take().unwrap());
+    let stderr = read_in_background(child.stderr.take().unwrap());
+    // Hooks share the spell's timeout, so a hung hook can't hold the spell up forever.
+    let status = match spell.timeout_ms {
+        None => child.wait().map(Some),
+        Some(timeout_ms) => wait_with_timeout(&mut child, timeout_ms),
+    };
+    let Some(status) = status.map_err(|e| failed(&e))? else {
+        return Err(failed(&format!("timed out after {} ms", spell.timeout_ms.unwrap_or_default())));
+    };
+    drop(stdout.join());
+    if !status.success() {
+        return Err(failed(&exit_message(status, &stderr.join().unwrap_or_default())));
+    }
+    Ok(())
+}
+
+// Hook failures are logged and emitted as spell-hook-error. Without an app
+// handle (test_spell) they are only logged.
+fn report_hook_error(app: Option<&AppHandle>, message: &str) {
+    log_warning(message);
+    if let Some(app) = app {
+        let _ = app.emit("spell-hook-error", message);
+    }
+}
+
+// A failing before hook only stops the spell when abortOnHookFailure is set.
+fn run_before_hook(spell: &LoadedSpell, input: &SpellInput, app: Option<&AppHandle>) -> Result<(), SpellError> {
+    let Some(cmd) = &spell.before_hook else { return Ok(()) };
+    match run_hook(spell, "before", cmd, input) {
+        Ok(()) => Ok(()),
+        Err(message) => {
+            report_hook_error(app, &message);
+            if spell.abort_on_hook_failure { Err(SpellError::HookFailed { message }) } else { Ok(()) }
+        }
+    }
+}
+
+// Started once the spell's process has exited. It runs on its own thread so
+// the output isn't held up by it.
+fn run_after_hook(spell: &LoadedSpell, input: &SpellInput, app: Option<&AppHandle>) {
+    let Some(cmd) = spell.after_hook.clone() else { return };
+    let (spell, input, app) = (spell.clone(), input.clone(), app.cloned());
+    std::thread::spawn(move || {
+        if let Err(e) = run_hook(&spell, "after", &cmd, &input) {
+            report_hook_error(app.as_ref(), &e);
+        }
+    });
+}
+
+// Each stderr line is also passed to on_stderr_line as it arrives, so status
+// messages from a slow spell can be shown while it runs.
+fn execute_spell(
+    spell: &LoadedSpell,
+    input: &SpellInput,
+    app: Option<&AppHandle>,
+    on_stderr_line: impl FnMut(&str) + Send + 'static,
+) -> Result<String, SpellError> {
+    execute_spell_with_stderr(spell, input, app, on_stderr_line).map(|(stdout, _)| stdout)
+}
+
+// Like execute_spell, also returning what a successful run printed to stderr
+// (warnings, progress), as (stdout, stderr).
+fn execute_spell_with_stderr(
+    spell: &LoadedSpell,
+    input: &SpellInput,
+    app: Option<&AppHandle>,
+    on_stderr_line: impl FnMut(&str) + Send + 'static,
+) -> Result<(String, String), SpellError> {
+    let (mut child, _image) = spawn_entry(spell, input, true, app)?;
+    // Drain both pipes concurrently so a chatty child can't block on a full
+    // pipe while we wait for its exit.
+    let stdout = read_in_background(child.stdout.take().unwrap());
+    let stderr = read_lines_in_background(child.stderr.take().unwrap(), on_stderr_line);
+    let status = match spell.timeout_ms {
+        None => child.wait().map(Some),
+        Some(timeout_ms) => wait_with_timeout(&mut child, timeout_ms),
+    };
+    // However the spell ended, timeouts included, its after hook still runs.
+    run_after_hook(spell, input, app);
+    let Some(status) = status? else {
+        return Err(SpellError::Timeout { timeout_ms: spell.timeout_ms.unwrap_or_default() });
+    };
+    let output = std::process::Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    };
+    if !output.status.success() {
+        return Err(SpellError::ExecutionFailed {
+            code: output.status.code(),
+            stderr: exit_message(output.status, &output.stderr),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        });
+    }
+    Ok((
+        String::from_utf8_lossy(&output.stdout).to_string(),
+        String::from_utf8_lossy(&output.stderr).to_string(),
+    ))
+}
+
+fn read_in_background(mut pipe: impl std::io::Read + Send + 'static) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = pipe.read_to_end(&mut bytes);
+        bytes
+    })
+}
+
+// Like read_in_background, also handing each complete line to on_line.
+fn read_lines_in_background(
+    pipe: impl std::io::Read + Send + 'static,
+    mut on_line: impl FnMut(&str) + Send + 'static,
+) -> std::thread::JoinHandle<Vec<u8>> {
+    use std::io::BufRead;
+
+    std::thread::spawn(move || {
+        let mut reader = std::io::BufReader::new(pipe);
+        let mut bytes = Vec::new();
+        loop {
+            let start = bytes.len();
+            match reader.read_until(b'\n', &mut bytes) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => on_line(trim_line_ending(&String::from_utf8_lossy(&bytes[start..]))),
+            }
+        }
+        bytes
+    })
+}
+
+// Uses the spell's stderr as the message, falling back to the exit status when it printed nothing.
+fn exit_message(status: std::process::ExitStatus, stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr).trim_end().to_string();
+    if stderr.is_empty() { format!("spell exited with {}", status) } else { stderr }
+}
+
+// Polls the child until it exits. Returns None if timeout_ms elapsed first, in
+// which case the child has been terminated.
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    timeout_ms: u64,
+) -> std::io::Result<Option<std::process::ExitStatus>> {
+    use std::time::{Duration, Instant};
+
+    // A timeout too far out to represent never expires.
+    let deadline = Instant::now().checked_add(Duration::from_millis(timeout_ms));
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            terminate_child(child);
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Asks the child to exit with SIGTERM so scripts can clean up, escalating to
+// SIGKILL if it is still running after a short grace period. Windows has no
+// SIGTERM equivalent for console processes, so it is killed outright.
+fn terminate_child(child: &mut std::process::Child) {
+    #[cfg(unix)]
+    {
+        use std::time::{Duration, Instant};
+
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+        let grace_deadline = Instant::now() + Duration::from_millis(500);
+        while Instant::now() < grace_deadline {
+            if let Ok(Some(_)) = child.try_wait() { return; }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// Length of a trailing multi-byte UTF-8 sequence that is still missing bytes.
+fn incomplete_utf8_suffix(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let b = bytes[bytes.len() - back];
+        if b & 0xC0 == 0x80 { continue; }
+        let needed = match b {
+            0xF0.. => 4,
+            0xE0.. => 3,
+            0xC0.. => 2,
+            _ => 1,
+        };
+        return if needed > back { back } else { 0 };
+    }
+    0
+}
+
+// Reads never split a character: an incomplete trailing sequence is held back
+// and prepended to the next read before decoding.
+fn pipe_to_channel(pipe: impl std::io::Read + Send + 'static) -> std::sync::mpsc::Receiver<String> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut pipe = pipe;
+        let mut buf = [0u8; 4096];
+        let mut pending = Vec::new();
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    pending.extend_from_slice(&buf[..n]);
+                    let complete = pending.len() - incomplete_utf8_suffix(&pending);
+                    if complete == 0 { continue; }
+                    let s = String::from_utf8_lossy(&pending[..complete]).into_owned();
+                    pending.drain(..complete);
+                    if tx.send(s).is_err() { return; }
+                }
+                Err(_) => break,
+            }
+        }
+        if !pending.is_empty() {
+            let _ = tx.send(String::from_utf8_lossy(&pending).into_owned());
+        }
+    });
+    rx
+}
+
+enum StreamEnd {
+    Finished,
+    TimedOut,
+    Cancelled,
+}
+
+// Calls on_flush every flush_interval with the text accumulated so far.
+// on_flush(chunk, is_final): is_final=true on the last call (process done or deadline hit).
+// Once cancelled is set nothing more is flushed, not even buffered text.
+fn stream_batched(
+    rx: std::sync::mpsc::Receiver<String>,
+    deadline: Option<std::time::Instant>,
+    flush_interval: std::time::Duration,
+    cancelled: &AtomicBool,
+    mut on_flush: impl FnMut(&str, bool),
+) -> StreamEnd {
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    // A zero interval would turn recv_timeout into a busy loop, whoever calls us.
+    let flush_interval = flush_interval.max(Duration::from_millis(MIN_STREAM_FLUSH_MS));
+    let mut buf = String::new();
+    loop {
+        let mut flush_at = Instant::now() + flush_interval;
+        if let Some(deadline) = deadline {
+            flush_at = flush_at.min(deadline);
+        }
+        loop {
+            if cancelled.load(Ordering::SeqCst) { return StreamEnd::Cancelled; }
+            let remaining = flush_at.saturating_duration_since(Instant::now());
+            if remaining.is_zero() { break; }
+            match rx.recv_timeout(remaining) {
+                Ok(chunk) => buf.push_str(&chunk),
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                // Cancelling closes the child's stdout too, so check the flag
+                // before treating the end of output as a normal finish.
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    if cancelled.load(Ordering::SeqCst) { return StreamEnd::Cancelled; }
+                    on_flush(&buf, true);
+                    return StreamEnd::Finished;
+                }
+            }
+        }
+        if cancelled.load(Ordering::SeqCst) { return StreamEnd::Cancelled; }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            on_flush(&buf, true);
+            return StreamEnd::TimedOut;
+        }
+        if !buf.is_empty() {
+            on_flush(&buf, false);
+            buf.clear();
+        }
+    }
+}
+
+// Flushing more often than this turns the stream loop into a busy loop.
+const MIN_STREAM_FLUSH_MS: u64 = 50;
+// Flushing less often than this makes a stream look stuck.
+const MAX_STREAM_FLUSH_MS: u64 = 2000;
+
+// The spell's streamFlushMs wins over the app-wide setting.
+fn stream_flush_interval(app: &AppHandle, spell: &LoadedSpell) -> std::time::Duration {
+    let ms = spell.stream_flush_ms.unwrap_or_else(|| current_config(app).stream_flush_ms);
+    std::time::Duration::from_millis(ms.clamp(MIN_STREAM_FLUSH_MS, MAX_STREAM_FLUSH_MS))
+}
+
+fn stream_deadline(timeout_ms: Option<u64>) -> Option<std::time::Instant> {
+    timeout_ms.and_then(|ms| std::time::Instant::now().checked_add(std::time::Duration::from_millis(ms)))
+}
+
+// Shared between a streaming thread and cancel_stream. The child stays in
+// the slot until finish_stream takes it out to reap it, so a signal sent while
+// holding the lock can never reach a recycled pid.
+#[derive(Clone)]
+struct StreamHandle {
+    cancelled: Arc<AtomicBool>,
+    child: Arc<Mutex<Option<std::process::Child>>>,
+}
+
+impl StreamHandle {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Some(child) = self.child.lock().unwrap().as_mut() {
+            #[cfg(unix)]
+            unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+            #[cfg(not(unix))]
+            let _ = child.kill();
+        }
+    }
+
+    fn same_stream(&self, other: &StreamHandle) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+    }
+}
+
+// Marks a spell as running until dropped: at the end of a command for
+// one-shot spells, or when the streaming thread exits for streams.
+struct SpellGuard(Arc<AtomicBool>);
+
+impl SpellGuard {
+    fn acquire(running: &RunningSpell) -> Option<SpellGuard> {
+        running.0.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).ok()?;
+        Some(SpellGuard(running.0.clone()))
+    }
+}
+
+impl Drop for SpellGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// Registers a new stream as the one cancel_stream stops, and under its
+// trigger for cancel_spell.
+fn begin_stream(app: &AppHandle, trigger: &str) -> StreamHandle {
+    let handle = StreamHandle {
+        cancelled: Arc::new(AtomicBool::new(false)),
+        child: Arc::new(Mutex::new(None)),
+    };
+    if let Some(active) = app.try_state::<ActiveStream>() {
+        *active.0.lock().unwrap() = Some(handle.clone());
+    }
+    if let Some(running) = app.try_state::<RunningProcesses>() {
+        running.0.lock().unwrap().insert(trigger.to_string(), handle.clone());
+    }
+    handle
+}
+
+// Forgets the stream unless a newer one has already replaced it.
+fn end_stream(app: &AppHandle, trigger: &str, handle: &StreamHandle) {
+    if let Some(active) = app.try_state::<ActiveStream>() {
+        let mut active = active.0.lock().unwrap();
+        if active.as_ref().is_some_and(|a| a.same_stream(handle)) {
+            *active = None;
+        }
+    }
+    if let Some(running) = app.try_state::<RunningProcesses>() {
+        let mut running = running.0.lock().unwrap();
+        if running.get(trigger).is_some_and(|r| r.same_stream(handle)) {
+            running.remove(trigger);
+        }
+    }
+}
+
+// The spell-stream-error payload. `stdout` is what was streamed before the
+// failure; exit_code is None when the spell never started or was killed by a signal.
+#[derive(Serialize, Clone)]
+struct StreamError {
+    stdout: String,
+    stderr: String,
+    exit_code: Option<i32>,
+}
+
+impl StreamError {
+    // A stream that failed before its spell could start.
+    fn not_started(e: &SpellError) -> StreamError {
+        StreamError { stdout: String::new(), stderr: e.to_string(), exit_code: None }
+    }
+}
+
+// Collects a stream's stderr for spell-stream-error, handing each chunk to
+// on_chunk as it arrives.
+fn pipe_stderr_to_channel(
+    pipe: std::process::ChildStderr,
+    mut on_chunk: impl FnMut(&str) + Send + 'static,
+) -> std::thread::JoinHandle<String> {
+    let rx = pipe_to_channel(pipe);
+    std::thread::spawn(move || {
+        let mut stderr = String::new();
+        for chunk in rx {
+            on_chunk(&chunk);
+            stderr.push_str(&chunk);
+        }
+        stderr
+    })
+}
+
+// A stream that never got going still ends like any other: spell-stream-end,
+// then spell-stream-error with why it didn't start.
+fn stream_not_started(app: &AppHandle, trigger: &str, handle: &StreamHandle, error: &SpellError) {
+    end_stream(app, trigger, handle);
+    let _ = app.emit("spell-stream-end", ());
+    let _ = app.emit("spell-stream-error", StreamError::not_started(error));
+}
+
+// Reaps a streaming child, reporting a timeout as spell-stream-timeout and a
+// non-zero exit as spell-stream-error with the stderr collected from it. Only
+// a stream that ran to a successful exit counts towards the spell's stats, and
+// only then is true returned. A cancelled stream's child is terminated and
+// spell-stream-end is emitted in place of the final flush.
+fn finish_stream(
+    app: &AppHandle,
+    spell: &LoadedSpell,
+    handle: &StreamHandle,
+    timeout_ms: Option<u64>,
+    end: StreamEnd,
+    stdout: &str,
+    stderr: std::thread::JoinHandle<String>,
+) -> bool {
+    end_stream(app, &spell.trigger, handle);
+    let Some(mut child) = handle.child.lock().unwrap().take() else { return false };
+    match end {
+        StreamEnd::TimedOut => {
+            let _ = app.emit("spell-stream-timeout", timeout_ms.unwrap_or_default());
+            terminate_child(&mut child);
+            false
+        }
+        StreamEnd::Cancelled => {
+            terminate_child(&mut child);
+            let _ = app.emit("spell-stream-end", ());
+            false
+        }
+        StreamEnd::Finished => {
+            let Ok(status) = child.wait() else { return false };
+            if status.success() {
+                record_run(app, spell);
+            } else {
+                let stderr = stderr.join().unwrap_or_default();
+                let stderr = if stderr.trim().is_empty() { exit_message(status, &[]) } else { stderr };
+                let error = StreamError { stdout: stdout.to_string(), stderr, exit_code: status.code() };
+                let _ = app.emit("spell-stream-error", error);
+            }
+            status.success()
+        }
+    }
+}
+
+fn start_spell_preview_stream(spell: LoadedSpell, input: SpellInput, app: AppHandle, guard: SpellGuard) {
+    let handle = begin_stream(&app, &spell.trigger);
+    std::thread::spawn(move || {
+        let _guard = guard;
+        let timeout_ms = spell.timeout_ms;
+        let (mut child, _image) = match spawn_entry(&spell, &input, true, Some(&app)) {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                stream_not_started(&app, &spell.trigger, &handle, &e);
+                return;
+            }
+        };
+        let rx = pipe_to_channel(child.stdout.take().unwrap());
+        let stderr_app = app.clone();
+        let stderr = pipe_stderr_to_channel(child.stderr.take().unwrap(), move |chunk| {
+            let _ = stderr_app.emit("spell-stream-stderr", chunk);
+        });
+        *handle.child.lock().unwrap() = Some(child);
+        let flush_interval = stream_flush_interval(&app, &spell);
+        let deadline = stream_deadline(timeout_ms);
+        let mut streamed = String::new();
+        let end = stream_batched(rx, deadline, flush_interval, &handle.cancelled, |chunk, is_final| {
+            if !chunk.is_empty() {
+                streamed.push_str(chunk);
+                let _ = app.emit("spell-stream", chunk);
+            }
+            if is_final {
+                let _ = app.emit("spell-stream-end", ());
+            }
+        });
+        finish_stream(&app, &spell, &handle, timeout_ms, end, &streamed, stderr);
+        run_after_hook(&spell, &input, Some(&app));
+    });
+}
+
+fn start_spell_type_stream(spell: LoadedSpell, input: SpellInput, app: AppHandle, guard: SpellGuard) {
+    let handle = begin_stream(&app, &spell.trigger);
+    std::thread::spawn(move || {
+        let _guard = guard;
+        let timeout_ms = spell.timeout_ms;
+        if let Err(e) = with_input_simulator(&app, |_| ()) {
+            stream_not_started(&app, &spell.trigger, &handle, &e);
+            return;
+        }
+        let (mut child, _image) = match spawn_entry(&spell, &input, true, Some(&app)) {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                stream_not_started(&app, &spell.trigger, &handle, &e);
+                return;
+            }
+        };
+        let rx = pipe_to_channel(child.stdout.take().unwrap());
+        let stderr = pipe_stderr_to_channel(child.stderr.take().unwrap(), |_| {});
+        *handle.child.lock().unwrap() = Some(child);
+        let cancelled = handle.cancelled.clone();
+        let flush_interval = stream_flush_interval(&app, &spell);
+        let deadline = stream_deadline(timeout_ms);
+        let mut typed = String::new();
+        let end = stream_batched(rx, deadline, flush_interval, &cancelled, |chunk, _is_final| {
+            // Re-check right before typing so a cancel never lets another batch through.
+            if !chunk.is_empty() && !cancelled.load(Ordering::SeqCst) {
+                typed.push_str(chunk);
+                // One dispatch per batch leaves the main thread free in between.
+                let (chunk, delay_ms, cancelled) = (chunk.to_string(), spell.type_delay_ms, cancelled.clone());
+                let _ = with_input_simulator(&app, move |enigo| type_text(enigo, &chunk, delay_ms, Some(&cancelled)));
+            }
+        });
+        finish_stream(&app, &spell, &handle, timeout_ms, end, &typed, stderr);
+        run_after_hook(&spell, &input, Some(&app));
+    });
+}
+
+// Streamed clipboardAppend output is buffered and appended in one go once the
+// spell exits successfully, so a failed, cancelled or timed-out run leaves the
+// clipboard as it was.
+fn start_spell_append_stream(
+    spell: LoadedSpell,
+    input: SpellInput,
+    app: AppHandle,
+    guard: SpellGuard,
+    before: Option<Arc<ClipboardSnapshot>>,
+) {
+    let handle = begin_stream(&app, &spell.trigger);
+    std::thread::spawn(move || {
+        let _guard = guard;
+        let timeout_ms = spell.timeout_ms;
+        let (mut child, _image) = match spawn_entry(&spell, &input, true, Some(&app)) {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                stream_not_started(&app, &spell.trigger, &handle, &e);
+                return;
+            }
+        };
+        let rx = pipe_to_channel(child.stdout.take().unwrap());
+        let stderr = pipe_stderr_to_channel(child.stderr.take().unwrap(), |_| {});
+        *handle.child.lock().unwrap() = Some(child);
+        let flush_interval = stream_flush_interval(&app, &spell);
+        let deadline = stream_deadline(timeout_ms);
+        let mut buffered = String::new();
+        let end = stream_batched(rx, deadline, flush_interval, &handle.cancelled, |chunk, _is_final| {
+            buffered.push_str(chunk);
+        });
+        if finish_stream(&app, &spell, &handle, timeout_ms, end, &buffered, stderr) {
+            append_clipboard_output(&spell, before.as_deref(), &buffered);
+        }
+        run_after_hook(&spell, &input, Some(&app));
+    });
+}
+
+// ---- Global shortcut ----
+
+fn default_shortcut() -> Shortcut {
+    Shortcut::new(Some(Modifiers::CONTROL), Code::Space)
+}
+
+fn parse_hotkey(hotkey: &HotkeyConfig) -> Result<Shortcut, String> {
+    let mut modifiers = Modifiers::empty();
+    for name in &hotkey.modifiers {
+        modifiers |= match name.to_lowercase().as_str() {
+            "ctrl" | "control" => Modifiers::CONTROL,
+            "alt" | "option" => Modifiers::ALT,
+            "shift" => Modifiers::SHIFT,
+            "super" | "cmd" | "command" | "meta" | "win" => Modifiers::SUPER,
+            _ => return Err(format!("Unknown modifier '{}' (expected Ctrl, Alt, Shift or Super)", name)),
+        };
+    }
+    // A lone key token parses as a modifier-less shortcut, which gives us the
+    // plugin's full key-name table (e.g. "Space", "K", "F5", "Backquote").
+    let code = hotkey.code.parse::<Shortcut>()
+        .map_err(|_| format!("Unknown key code '{}' (expected a key name such as Space, K or F5)", hotkey.code))?
+        .key;
+    Ok(Shortcut::new(Some(modifiers), code))
+}
+
+// Reads what the simulated copy put on the clipboard. Text wins; an image
+// (e.g. a copied screenshot region) is handed over as PNG.
+fn copied_input() -> SpellInput {
+    let Ok(mut clipboard) = arboard::Clipboard::new() else { return SpellInput::default() };
+    if let Ok(text) = clipboard.get_text() {
+        return SpellInput::Text(text);
+    }
+    clipboard.get_image().ok()
+        .and_then(|image| encode_png(&image))
+        .map(SpellInput::Image)
+        .unwrap_or_default()
+}
+
+fn encode_png(image: &arboard::ImageData) -> Option<Vec<u8>> {
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, image.width as u32, image.height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().ok()?.write_image_data(&image.bytes).ok()?;
+    Some(png)
+}
+
+// Reads a PNG file into the RGBA pixels arboard wants, whatever its color type.
+fn decode_png(path: &Path) -> Option<arboard::ImageData<'static>> {
+    let mut decoder = png::Decoder::new(std::fs::File::open(path).ok()?);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().ok()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).ok()?;
+    pixels.truncate(info.buffer_size());
+    let rgba: Vec<u8> = match info.color_type {
+        png::ColorType::Rgba => pixels,
+        png::ColorType::Rgb => pixels.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => pixels.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+        png::ColorType::Indexed => return None,
+    };
+    Some(arboard::ImageData {
+        width: info.width as usize,
+        height: info.height as usize,
+        bytes: rgba.into(),
+    })
+}
+
+// Clipboard output: a spell that prints the path of a .png file puts that
+// image on the clipboard; anything else is copied as text.
+fn set_clipboard_output(spell: &LoadedSpell, output: &str) {
+    let Ok(mut clipboard) = arboard::Clipboard::new() else { return };
+    let text = trim_line_ending(output);
+    let path = spell.collection_dir.join(text.trim());
+    let is_png = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    if is_png && !text.contains('\n') && path.is_file() {
+        if let Some(image) = decode_png(&path) {
+            let _ = clipboard.set_image(image);
+            return;
+        }
+        log_warning(format!("could not read {} as a PNG image, copying the path instead", path.display()));
+    }
+    let _ = clipboard.set_text(text);
+}
+
+// ClipboardAppend output. `before` is what the user had copied before the
+// shortcut, since by now the clipboard usually holds the selection from the
+// simulated copy; without it the current clipboard is used. An empty (or
+// image) clipboard just gets the output, like Clipboard mode.
+fn append_clipboard_output(spell: &LoadedSpell, before: Option<&ClipboardSnapshot>, output: &str) {
+    let Ok(mut clipboard) = arboard::Clipboard::new() else { return };
+    let existing = match before {
+        Some(ClipboardSnapshot::Text(text)) => Some(text.clone()),
+        Some(ClipboardSnapshot::Image(_)) => None,
+        None => clipboard.get_text().ok(),
+    };
+    let output = trim_line_ending(output);
+    let combined = match existing.filter(|text| !text.is_empty()) {
+        Some(existing) => format!("{}{}{}", existing, spell.append_separator.as_deref().unwrap_or("\n"), output),
+        None => output.to_string(),
+    };
+    let _ = clipboard.set_text(combined);
+}
+
+fn on_activate(app: &AppHandle, event: ShortcutEvent) {
+    if event.state != ShortcutState::Pressed { return; }
+    if app.try_state::<Paused>().is_some_and(|paused| *paused.0.lock().unwrap()) { return; }
+
+    if let Some(state) = app.try_state::<PrevWindow>() {
+        save_prev_window(app, &state);
+    }
+
+    let gen_before = clipboard_generation();
+    let before = ClipboardSnapshot::read().map(Arc::new);
+
+    match input_simulator() {
+        Ok(mut enigo) => simulate_copy(&mut enigo),
+        Err(e) => log_warning(format!("could not copy the selection: {}", e)),
+    }
+
+    std::thread::sleep(std::time::Duration::from_millis(current_config(app).copy_settle_ms));
+
+    // The clipboard generation counter increments on every clipboard update,
+    // even when the new content is identical to the old content. This correctly
+    // handles two edge cases that a simple before/after content comparison fails:
+    //   - Selecting the same text twice: counter increments → detected as selection.
+    //   - Selecting nothing: Ctrl+C has no effect → counter unchanged → empty input.
+    let copied = clipboard_generation() != gen_before;
+    let selected = if copied {
+        copied_input()
+    } else {
+        SpellInput::Text(fallback_selection())
+    };
+    if let Some(state) = app.try_state::<SelectedInput>() {
+        *state.0.lock().unwrap() = selected;
+    }
+    if let Some(state) = app.try_state::<ClipboardInput>() {
+        *state.0.lock().unwrap() = before.clone();
+    }
+    // Only worth restoring if the simulated copy actually replaced it.
+    if let Some(state) = app.try_state::<OriginalClipboard>() {
+        *state.0.lock().unwrap() = before.filter(|_| copied);
+    }
+
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
+
+// ---- Tauri commands ----
+
+fn spell_info(spell: &LoadedSpell, stats: &HashMap<String, SpellStats>, favorites: &HashSet<String>) -> SpellInfo {
+    SpellInfo {
+        trigger: spell.trigger.clone(),
+        description: spell.description.clone(),
+        category: spell.category.clone(),
+        icon: spell.icon.clone(),
+        collection: spell.collection_name.clone(),
+        collection_path: spell.collection_dir.clone(),
+        primary_trigger: spell.primary_trigger.clone(),
+        aliases: spell.aliases.clone(),
+        output_mode: spell.output_mode.clone(),
+        stream_mode: spell.stream_mode,
+        run_count: stats.get(&spell.trigger).map(|s| s.run_count).unwrap_or(0),
+        is_favorite: favorites.contains(&spell.trigger),
+    }
+}
+
+fn spell_category(spell: &LoadedSpell) -> &str {
+    spell.category.as_deref().unwrap_or(UNCATEGORIZED)
+}
+
+fn same_category(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+// With favorites_first set, starred spells are pinned above the rest, each
+// group keeping the sort_by order.
+#[tauri::command]
+fn get_spells(
+    sort_by: Option<SortMode>,
+    favorites_first: Option<bool>,
+    store: tauri::State<'_, SpellStore>,
+    stats: tauri::State<'_, StatsStore>,
+    favorites: tauri::State<'_, Favorites>,
+) -> Vec<SpellInfo> {
+    let stats = stats.0.lock().unwrap();
+    let favorites = favorites.0.lock().unwrap();
+    let spells = store.0.lock().unwrap();
+    let mut spells: Vec<&LoadedSpell> = spells.iter().filter(|s| s.primary_trigger.is_none()).collect();
+    let stat = |s: &LoadedSpell| stats.get(&s.trigger);
+    match sort_by.unwrap_or_default() {
+        SortMode::Priority => spells.sort_by(|a, b| b.priority.cmp(&a.priority).then_with(|| a.trigger.cmp(&b.trigger))),
+        SortMode::Trigger => spells.sort_by(|a, b| a.trigger.cmp(&b.trigger)),
+        SortMode::LastUsed => spells.sort_by_key(|s| std::cmp::Reverse(stat(s).and_then(|st| st.last_run_at))),
+        SortMode::RunCount => spells.sort_by_key(|s| std::cmp::Reverse(stat(s).map(|st| st.run_count).unwrap_or(0))),
+    }
+    if favorites_first == Some(true) {
+        spells.sort_by_key(|s| !favorites.contains(&s.trigger));
+    }
+    spells.into_iter().map(|s| spell_info(s, &stats, &favorites)).collect()
+}
+
+// Stars or unstars a spell and returns whether it is now a favorite.
+#[tauri::command]
+fn toggle_favorite(trigger: String, favorites: tauri::State<'_, Favorites>) -> Result<bool, String> {
+    let mut favorites = favorites.0.lock().unwrap();
+    let is_favorite = if favorites.remove(&trigger) {
+        false
+    } else {
+        favorites.insert(trigger);
+        true
+    };
+    save_favorites(&get_favorites_path(), &favorites)?;
+    Ok(is_favorite)
+}
+
+// Favorites that are currently loaded, in load order.
+#[tauri::command]
+fn get_favorite_spells(
+    store: tauri::State<'_, SpellStore>,
+    stats: tauri::State<'_, StatsStore>,
+    favorites: tauri::State<'_, Favorites>,
+) -> Vec<SpellInfo> {
+    let stats = stats.0.lock().unwrap();
+    let favorites = favorites.0.lock().unwrap();
+    store.0.lock().unwrap()
+        .iter()
+        .filter(|s| s.primary_trigger.is_none() && favorites.contains(&s.trigger))
+        .map(|s| spell_info(s, &stats, &favorites))
+        .collect()
+}
+
+// Distinct category names in load order. Names differing only in case are
+// merged, keeping the first spelling seen.
+#[tauri::command]
+fn get_spell_categories(store: tauri::State<'_, SpellStore>) -> Vec<String> {
+    let mut categories: Vec<String> = Vec::new();
+    for spell in store.0.lock().unwrap().iter() {
+        let category = spell_category(spell);
+        if !categories.iter().any(|c| same_category(c, category)) {
+            categories.push(category.to_string());
+        }
+    }
+    categories
+}
+
+#[tauri::command]
+fn get_spells_by_category(
+    category: String,
+    store: tauri::State<'_, SpellStore>,
+    stats: tauri::State<'_, StatsStore>,
+    favorites: tauri::State<'_, Favorites>,
+) -> Vec<SpellInfo> {
+    let stats = stats.0.lock().unwrap();
+    let favorites = favorites.0.lock().unwrap();
+    store.0.lock().unwrap()
+        .iter()
+        .filter(|s| s.primary_trigger.is_none() && same_category(spell_category(s), &category))
+        .map(|s| spell_info(s, &stats, &favorites))
+        .collect()
+}
+
+#[tauri::command]
+fn get_collections(store: tauri::State<'_, CollectionStore>) -> Vec<CollectionInfo> {
+    store.0.lock().unwrap()
+        .iter()
+        .map(|c| CollectionInfo {
+            name: collection_name(&c.meta, &c.dir),
+            description: c.meta.description.clone(),
+            author: c.meta.author.clone(),
+            version: c.meta.version.clone(),
+            path: c.dir.clone(),
+            spell_count: c.spells.iter().filter(|s| s.primary_trigger.is_none()).count(),
+            format: c.collection_format,
+            disabled_spells: c.disabled.clone(),
+        })
+        .collect()
+}
+
+// Swaps alias entries for the spell they stand in for, keeping each spell once
+// at its best position. Lists advertise canonical triggers; aliases only help
+// a spell match.
+fn canonical_spells<'a>(spells: &'a [LoadedSpell], hits: Vec<&'a LoadedSpell>) -> Vec<&'a LoadedSpell> {
+    let mut seen = HashSet::new();
+    hits.into_iter()
+        .map(|hit| match &hit.primary_trigger {
+            Some(primary) => spells.iter()
+                .find(|s| s.primary_trigger.is_none() && s.trigger == *primary && s.collection_dir == hit.collection_dir)
+                .unwrap_or(hit),
+            None => hit,
+        })
+        .filter(|s| seen.insert((s.collection_dir.as_path(), s.trigger.as_str())))
+        .collect()
+}
+
+#[tauri::command]
+fn search_spells(
+    query: String,
+    store: tauri::State<'_, SpellStore>,
+    stats: tauri::State<'_, StatsStore>,
+    favorites: tauri::State<'_, Favorites>,
+) -> Vec<SpellInfo> {
+    let stats = stats.0.lock().unwrap();
+    let favorites = favorites.0.lock().unwrap();
+    let spells = store.0.lock().unwrap();
+    canonical_spells(&spells, rank_spells(&query, &spells))
+        .into_iter()
+        .map(|s| spell_info(s, &stats, &favorites))
+        .collect()
+}
+
+// The spells used most often and most recently, best first, for the
+// top of the list before anything is typed.
+#[tauri::command]
+fn get_recent_spells(
+    limit: usize,
+    order: Option<RecentOrder>,
+    store: tauri::State<'_, SpellStore>,
+    stats: tauri::State<'_, StatsStore>,
+    favorites: tauri::State<'_, Favorites>,
+) -> Vec<SpellInfo> {
+    let stats = stats.0.lock().unwrap();
+    let favorites = favorites.0.lock().unwrap();
+    let spells = store.0.lock().unwrap();
+    let now = unix_now();
+    let rank = |st: &SpellStats| match order.unwrap_or_default() {
+        RecentOrder::Frecency => frecency(st, now),
+        RecentOrder::LastUsed => st.last_run_at.unwrap_or(0) as f64,
+    };
+    let mut recent: Vec<(f64, &LoadedSpell)> = spells.iter()
+        .filter(|s| s.primary_trigger.is_none())
+        .filter_map(|s| stats.get(&s.trigger).map(|st| (rank(st), s)))
+        .collect();
+    recent.sort_by(|a, b| b.0.total_cmp(&a.0));
+    recent.into_iter().take(limit).map(|(_, s)| spell_info(s, &stats, &favorites)).collect()
+}
+
+#[tauri::command]
+fn get_spell_stats(stats: tauri::State<'_, StatsStore>) -> Vec<SpellStats> {
+    stats.0.lock().unwrap().values().cloned().collect()
+}
+
+#[tauri::command]
+fn reset_spell_stats(app: AppHandle, stats: tauri::State<'_, StatsStore>) {
+    stats.0.lock().unwrap().clear();
+    save_stats_in_background(&app);
+}
+
+#[tauri::command]
+fn get_load_diagnostics(diagnostics: tauri::State<'_, LoadDiagnostics>) -> Vec<LoadDiagnostic> {
+    diagnostics.0.lock().unwrap().clone()
+}
+
+// The warnings of the last load as spell-load-warning would carry them. The
+// startup load happens before the webview can listen, so it is fetched instead.
+#[tauri::command]
+fn get_load_warnings(diagnostics: tauri::State<'_, LoadDiagnostics>) -> Vec<String> {
+    load_warnings(&diagnostics.0.lock().unwrap())
+}
+
+// Counts and timing of the last collection load.
+#[tauri::command]
+fn get_load_stats(stats: tauri::State<'_, LastLoadStats>) -> LoadStats {
+    *stats.0.lock().unwrap()
+}
+
+#[tauri::command]
+fn get_load_errors(errors: tauri::State<'_, LoadErrors>) -> Vec<(PathBuf, String)> {
+    errors.0.lock().unwrap().clone()
+}
+
+// Also re-reads favorites.json, in case it was edited by hand or synced.
+#[tauri::command]
+fn refresh_spells(app: AppHandle, favorites: tauri::State<'_, Favorites>) {
+    *favorites.0.lock().unwrap() = load_favorites(&get_favorites_path());
+    reload_spells(&app);
+}
+
+// Stops the running preview or type stream: no further output is shown or
+// typed, and the spell process is terminated.
+#[tauri::command]
+fn cancel_stream(active: tauri::State<'_, ActiveStream>) {
+    if let Some(handle) = active.0.lock().unwrap().take() {
+        handle.cancel();
+    }
+}
+
+// Closing the picker without running a spell also puts back the clipboard the
+// simulated copy overwrote. Once a spell has run, apply_spell owns the snapshot.
+#[tauri::command]
+fn cancel(
+    app: AppHandle,
+    prev_window: tauri::State<'_, PrevWindow>,
+    original_clipboard: tauri::State<'_, OriginalClipboard>,
+) {
+    if let Some(original) = original_clipboard.0.lock().unwrap().take() {
+        original.restore();
+    }
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.hide();
+    }
+    let prev = *prev_window.0.lock().unwrap();
+    restore_prev_window(prev);
+}
+
+// Stops the running stream of one spell and emits spell-cancelled with its trigger.
+#[tauri::command]
+fn cancel_spell(
+    trigger: String,
+    app: AppHandle,
+    running: tauri::State<'_, RunningProcesses>,
+) -> Result<(), String> {
+    let handle = running.0.lock().unwrap()
+        .remove(&trigger)
+        .ok_or_else(|| format!("Spell '{}' is not running", trigger))?;
+    handle.cancel();
+    let _ = app.emit("spell-cancelled", trigger);
+    Ok(())
+}
+
+// Clicking away from the palette dismisses it like cancel does. Focus goes
+// back to the previous window only when the click didn't land in another app
+// (which keeps focus then). Does nothing if cancel or a spell already hid the
+// window, or while a spell is still running in it.
+fn on_palette_blur(window: &tauri::Window) {
+    let app = window.app_handle();
+    if !window.is_visible().unwrap_or(false) { return; }
+    if app.state::<RunningSpell>().0.load(Ordering::SeqCst) { return; }
+    let _ = window.hide();
+    if let Some(original) = app.state::<OriginalClipboard>().0.lock().unwrap().take() {
+        original.restore();
+    }
+    let prev = *app.state::<PrevWindow>().0.lock().unwrap();
+    let now = foreground_window();
+    if now == 0 || now == prev || is_own_window(now) {
+        restore_prev_window(prev);
+    }
+}
+
+// With fuzzy_match set, a trigger that doesn't match exactly resolves to the
+// best search_spells result for it.
+fn find_spell(store: &SpellStore, trigger: &str, fuzzy_match: bool) -> Result<LoadedSpell, SpellError> {
+    let spells = store.0.lock().unwrap();
+    let spell = spells.iter()
+        .find(|s| s.trigger == trigger)
+        .or_else(|| {
+            if !fuzzy_match { return None; }
+            rank_spells(trigger, &spells).into_iter().next()
+        })
+        .cloned()
+        .ok_or_else(|| SpellError::NotFound { trigger: trigger.to_string() })?;
+    Ok(spell)
+}
+
+// Sets "enabled" on one spell entry of an index file, keeping key order.
+// TOML and YAML also keep their comments and formatting. JSON has no comments
+// and is written back pretty-printed, as add_spell does.
+fn write_spell_enabled(index_path: &Path, position: usize, enabled: bool) -> Result<(), String> {
+    let content = std::fs::read_to_string(index_path).map_err(|e| e.to_string())?;
+    let missing = || format!("{} no longer has spell #{}", index_path.display(), position + 1);
+    let updated = match index_path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => {
+            let mut doc: serde_yaml::Value = serde_yaml::from_str(&content).map_err(|e| e.to_string())?;
+            doc.get_mut("spells").and_then(|s| s.get_mut(position))
+                .and_then(|s| s.as_mapping_mut()).ok_or_else(missing)?
+                .insert("enabled".into(), enabled.into());
+            // The line edit only understands block-style entries, so it is
+            // checked against the structured edit before anything is written.
+            let unsupported = || format!(
+                "{} is laid out in a way that can't be edited in place; set \"enabled\" on spell #{} by hand",
+                index_path.display(), position + 1,
+            );
+            let updated = set_yaml_spell_enabled(&content, position, enabled).ok_or_else(unsupported)?;
+            if serde_yaml::from_str::<serde_yaml::Value>(&updated).ok() != Some(doc) {
+                return Err(unsupported());
+            }
+            updated
+        }
+        Some("toml") => {
+            let mut doc: toml_edit::Document = content.parse().map_err(|e: toml_edit::TomlError| e.to_string())?;
+            let spell = doc.get_mut("spells").and_then(|s| s.get_mut(position))
+                .and_then(|s| s.as_table_like_mut()).ok_or_else(missing)?;
+            spell.insert("enabled", toml_edit::value(enabled));
+            doc.to_string()
+        }
+        _ => {
+            let mut doc: serde_json::Value = serde_json::from_str(&content).map_err(|e| e.to_string())?;
+            doc.get_mut("spells").and_then(|s| s.get_mut(position))
+                .and_then(|s| s.as_object_mut()).ok_or_else(missing)?
+                .insert("enabled".into(), enabled.into());
+            serde_json::to_string_pretty(&doc).map_err(|e| e.to_string())?
+        }
+    };
+    std::fs::write(index_path, updated).map_err(|e| e.to_string())
+}
+
+// Replaces the spell's "enabled" line, or adds one after the spell's last
+// line. Only block-style "spells:" lists are handled; flow style gives None.
+fn set_yaml_spell_enabled(content: &str, position: usize, enabled: bool) -> Option<String> {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let indent = |line: &str| line.len() - line.trim_start_matches(' ').len();
+    let is_content = |line: &str| {
+        let trimmed = line.trim();
+        !trimmed.is_empty() && !trimmed.starts_with('#')
+    };
+    let spells_line = lines.iter().position(|line| {
+        line.strip_prefix("spells:").is_some_and(|rest| {
+            let rest = rest.trim();
+            rest.is_empty() || rest.starts_with('#')
+        })
+    })?;
+    // Where each "- " item starts, and where the list ends.
+    let mut items = Vec::new();
+    let mut seq_indent = None;
+    let mut end = lines.len();
+    for (i, line) in lines.iter().enumerate().skip(spells_line + 1) {
+        if !is_content(line) {
+            continue;
+        }
+        let at = indent(line);
+        let is_item = line[at..].starts_with('-');
+        match seq_indent {
+            None if is_item => seq_indent = Some(at),
+            None => return None,
+            Some(seq) if at < seq || (at == seq && !is_item) => {
+                end = i;
+                break;
+            }
+            Some(_) => {}
+        }
+        if is_item && Some(at) == seq_indent {
+            items.push(i);
+        }
+    }
+    let item_start = *items.get(position)?;
+    let item_end = items.get(position + 1).copied().unwrap_or(end);
+    let dash_line = lines[item_start];
+    let dash = indent(dash_line);
+    let after_dash = &dash_line[dash + 1..];
+    let key_col = if after_dash.trim().is_empty() {
+        lines[item_start + 1..item_end].iter().find(|line| is_content(line)).map(|line| indent(line))?
+    } else {
+        let col = dash + 1 + (after_dash.len() - after_dash.trim_start_matches(' ').len());
+        if dash_line[col..].starts_with(['{', '[', '&', '!', '|', '>']) {
+            return None;
+        }
+        col
+    };
+    let value = if enabled { "true" } else { "false" };
+    let mut updated: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+    let existing = (item_start..item_end).find(|&i| {
+        let line = lines[i];
+        (i == item_start || indent(line) == key_col) && line.get(key_col..).is_some_and(|rest| rest.starts_with("enabled:"))
+    });
+    match existing {
+        Some(i) => {
+            let line = lines[i];
+            let body = line.trim_end_matches(['\r', '\n']);
+            let eol = &line[body.len()..];
+            let rest = &body[key_col + "enabled:".len()..];
+            let comment = rest.find(" #").map(|at| &rest[at..]).unwrap_or("");
+            updated[i] = format!("{}enabled: {}{}{}", &body[..key_col], value, comment, eol);
+        }
+        None => {
+            let last = (item_start..item_end).rev().find(|&i| is_content(lines[i]))?;
+            let eol = if content.contains("\r\n") { "\r\n" } else { "\n" };
+            if !updated[last].ends_with('\n') {
+                updated[last].push_str(eol);
+            }
+            updated.insert(last + 1, format!("{}enabled: {}{}", " ".repeat(key_col), value, eol));
+        }
+    }
+    Some(updated.concat())
+}
+
+// Enables or disables a spell by editing its collection's index file, then
+// reloads so the change shows up straight away.
+#[tauri::command]
+fn set_spell_enabled(
+    trigger: String,
+    enabled: bool,
+    app: AppHandle,
+    store: tauri::State<'_, SpellStore>,
+) -> Result<(), String> {
+    let loaded = store.0.lock().unwrap()
+        .iter()
+        .find(|s| s.trigger == trigger)
+        .map(|s| (s.collection_dir.clone(), s.index_position));
+    // Disabled spells aren't loaded, so they are looked up in their collection.
+    let (collection_dir, position) = loaded
+        .or_else(|| {
+            app.state::<CollectionStore>().0.lock().unwrap().iter().find_map(|c| {
+                c.disabled.iter().find(|s| s.trigger == trigger).map(|s| (c.dir.clone(), s.index_position))
+            })
+        })
+        .ok_or_else(|| format!("Spell '{}' not found", trigger))?;
+    let index_path = find_index_file(&collection_dir)
+        .ok_or_else(|| format!("{} has no index file", collection_dir.display()))?;
+    write_spell_enabled(&index_path, position, enabled)?;
+    reload_spells(&app);
+    Ok(())
+}
+
+// Adds a spell running `command` to the collection folder of that name in the
+// primary collections directory, creating the folder and its index.json when
+// they don't exist yet. Collections indexed in YAML or TOML are left alone.
+#[tauri::command]
+fn create_spell(
+    collection: String,
+    trigger: String,
+    description: Option<String>,
+    command: String,
+    app: AppHandle,
+    store: tauri::State<'_, SpellStore>,
+) -> Result<(), String> {
+    let trigger = trigger.trim().to_string();
+    if trigger.is_empty() || command.trim().is_empty() {
+        return Err("a spell needs a trigger and a command".to_string());
+    }
+    if store.0.lock().unwrap().iter().any(|s| s.trigger == trigger) {
+        return Err(format!("Spell '{}' already exists", trigger));
+    }
+    let folder = collection.trim();
+    if folder.is_empty() || folder.starts_with('.') || folder.contains(['/', '\\']) {
+        return Err(format!("'{}' is not a valid collection folder name", collection));
+    }
+    let dir = app.state::<CollectionsDirs>().0.lock().unwrap()
+        .first()
+        .cloned()
+        .ok_or("no collections directory is configured")?
+        .join(folder);
+
+    let mut spell = serde_json::json!({ "trigger": trigger, "entry": { "default": command } });
+    if let Some(description) = description.filter(|d| !d.trim().is_empty()) {
+        spell["description"] = description.into();
+    }
+    let index_path = dir.join("index.json");
+    let doc = match find_index_file(&dir) {
+        Some(existing) if existing != index_path => {
+            return Err(format!("{} is not JSON; add the spell to it by hand", existing.display()));
+        }
+        Some(_) => {
+            let content = std::fs::read_to_string(&index_path).map_err(|e| e.to_string())?;
+            let mut doc: serde_json::Value = serde_json::from_str(&content)
+                .map_err(|e| format!("{}: {}", index_path.display(), e))?;
+            doc.get_mut("spells")
+                .and_then(|spells| spells.as_array_mut())
+                .ok_or_else(|| format!("{} has no spells array", index_path.display()))?
+                .push(spell);
+            doc
+        }
+        None => serde_json::json!({ "name": folder, "spells": [spell] }),
+    };
+    std::fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let content = serde_json::to_string_pretty(&doc).map_err(|e| e.to_string())?;
+    std::fs::write(&index_path, content).map_err(|e| format!("{}: {}", index_path.display(), e))?;
+    reload_spells(&app);
+    Ok(())
+}
+
+// Runs a non-streaming spell to completion. Successful runs are counted in stats.
+fn run_to_output(app: &AppHandle, spell: &LoadedSpell, input: &SpellInput) -> Result<String, SpellError> {
+    let _ = app.emit("spell-running", &spell.trigger);
+    let progress_app = app.clone();
+    let result = execute_spell(spell, input, Some(app), move |line| {
+        let _ = progress_app.emit("spell-progress", line);
+    });
+    let _ = app.emit("spell-finished", &spell.trigger);
+    let output = result?;
+    record_run(app, spell);
+    Ok(output)
+}
+
+// Dry run for spell authors: runs the spell on the given text and returns its
+// output as a preview, whatever the output mode. The clipboard, focus and
+// usage stats are left alone. A spell with params needs them here as well.
+#[tauri::command]
+fn test_spell(
+    trigger: String,
+    input: String,
+    params: Option<HashMap<String, String>>,
+    store: tauri::State<'_, SpellStore>,
+) -> Result<SpellResult, SpellError> {
+    let mut spell = store.0.lock().unwrap()
+        .iter()
+        .find(|s| s.trigger == trigger)
+        .cloned()
+        .ok_or(SpellError::NotFound { trigger })?;
+    if !spell.params.is_empty() {
+        let Some(params) = params else {
+            return Err(format!("Spell '{}' takes params; test it with params set", spell.trigger).into());
+        };
+        spell.param_values = resolve_params(&spell, &params)?;
+    }
+    let content = execute_spell(&spell, &SpellInput::Text(input), None, |_| {})?;
+    Ok(SpellResult::Preview { content, format: spell.preview_format })
+}
+
+// Expands a leading ~, ${VAR} and {{date}} (local YYYY-MM-DD); relative paths
+// are taken from the spell's collection folder.
+fn resolve_output_path(template: &str, collection_dir: &Path) -> PathBuf {
+    let expanded = expand_env_vars(template)
+        .replace("{{date}}", &chrono::Local::now().format("%Y-%m-%d").to_string());
+    let path = if expanded == "~" {
+        get_home_dir()
+    } else if let Some(rest) = expanded.strip_prefix("~/").or_else(|| expanded.strip_prefix("~\\")) {
+        get_home_dir().join(rest)
+    } else {
+        PathBuf::from(expanded)
+    };
+    collection_dir.join(path)
+}
+
+fn write_output_file(spell: &LoadedSpell, output: &str) -> Result<(), SpellError> {
+    let template = spell.output_path.as_deref()
+        .ok_or_else(|| format!("Spell '{}' uses file output but has no outputPath", spell.trigger))?;
+    let path = resolve_output_path(template, &spell.collection_dir);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Appended output goes on a line of its own, so a log spell adds one entry per run.
+    let result = if spell.output_append {
+        use std::io::Write;
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", trim_line_ending(output)))
+    } else {
+        std::fs::write(&path, trim_line_ending(output))
+    };
+    result.map_err(|e| SpellError::IoError { message: format!("could not write {}: {}", path.display(), e) })
+}
+
+// Tags that end a line in the plain-text fallback.
+const HTML_LINE_BREAK_TAGS: &[&str] = &["br", "/p", "/div", "/li", "/h1", "/h2", "/h3", "/h4", "/h5", "/h6", "/tr"];
+
+// The plain-text fallback for HTML output: tags removed, block ends turned
+// into newlines and the common entities decoded. Not a real HTML parser.
+fn strip_html_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut tag: Option<String> = None;
+    for c in html.chars() {
+        match (&mut tag, c) {
+            (None, '<') => tag = Some(String::new()),
+            (Some(name), '>') => {
+                let name = name.split_whitespace().next().unwrap_or("").trim_end_matches('/').to_lowercase();
+                if HTML_LINE_BREAK_TAGS.contains(&name.as_str()) {
+                    text.push('\n');
+                }
+                tag = None;
+            }
+            (Some(name), c) => name.push(c),
+            (None, c) => text.push(c),
+        }
+    }
+    // A '<' that never closed was text after all.
+    if let Some(rest) = tag {
+        text.push('<');
+        text.push_str(&rest);
+    }
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+        .trim_end()
+        .to_string()
+}
+
+fn notify_output(app: &AppHandle, title: &str, output: &str) {
+    let body = output.trim();
+    let _ = app.notification()
+        .builder()
+        .title(title)
+        .body(if body.is_empty() { "Done" } else { body })
+        .show();
+}
+
+// Runs a spell on the given text instead of the captured selection. Nothing
+// is pasted or typed and the window stays put: paste and type spells return
+// their output as a preview, and streaming is only kept for preview spells.
+// A spell with params needs them here as well, and one marked confirm returns
+// NeedsConfirm until confirmed is set.
+#[tauri::command]
+fn run_spell_with_input(
+    trigger: String,
+    input: String,
+    params: Option<HashMap<String, String>>,
+    confirmed: Option<bool>,
+    app: AppHandle,
+    store: tauri::State<'_, SpellStore>,
+    running: tauri::State<'_, RunningSpell>,
+) -> Result<SpellResult, SpellError> {
+    let mut spell = find_spell(&store, &trigger, false)?;
+    if !spell.params.is_empty() {
+        let Some(params) = params else {
+            return Err(format!("Spell '{}' takes params; run it with params set", spell.trigger).into());
+        };
+        spell.param_values = resolve_params(&spell, &params)?;
+    }
+    if spell.confirm && confirmed != Some(true) {
+        return Ok(SpellResult::NeedsConfirm { trigger: spell.trigger });
+    }
+    let Some(guard) = SpellGuard::acquire(&running) else { return Ok(SpellResult::Busy) };
+    let input = SpellInput::Text(input);
+
+    if spell.output_mode == OutputMode::Preview && spell.stream_mode {
+        start_spell_preview_stream(spell, input, app, guard);
+        return Ok(SpellResult::Stream);
+    }
+    if spell.output_mode == OutputMode::ClipboardAppend && spell.stream_mode {
+        start_spell_append_stream(spell, input, app, guard, None);
+        return Ok(SpellResult::Done);
+    }
+
+    let output = run_to_output(&app, &spell, &input)?;
+    match spell.output_mode {
+        OutputMode::None => {}
+        OutputMode::Clipboard => set_clipboard_output(&spell, &output),
+        OutputMode::ClipboardAppend => append_clipboard_output(&spell, None, &output),
+        OutputMode::Notify => notify_output(&app, &spell.trigger, &output),
+        OutputMode::File => write_output_file(&spell, &output)?,
+        OutputMode::Preview
+        | OutputMode::Paste
+        | OutputMode::Type
+        | OutputMode::Append
+        | OutputMode::Prepend
+        | OutputMode::Html => {
+            return Ok(SpellResult::Preview { content: output, format: spell.preview_format });
+        }
+    }
+    Ok(SpellResult::Done)
 }
 
-// ---- Tauri commands ----
+// What a spell would run, without running it, for debugging spells.
+#[tauri::command]
+fn describe_spell(trigger: String, store: tauri::State<'_, SpellStore>) -> Result<SpellDetail, String> {
+    let spells = store.0.lock().unwrap();
+    let spell = spells.iter()
+        .find(|s| s.trigger == trigger)
+        .ok_or_else(|| format!("Spell '{}' not found", trigger))?;
+    Ok(SpellDetail {
+        trigger: spell.trigger.clone(),
+        primary_trigger: spell.primary_trigger.clone(),
+        entry_cmd: spell.entry_cmd.clone(),
+        shell: spell.shell.program().to_string(),
+        shell_args: spell.shell_args.clone(),
+        interpreter: spell.script.as_ref().map(|s| s.interpreter.clone()),
+        script: spell.script.as_ref().map(|s| spell.collection_dir.join(&s.script)),
+        args: spell.args.clone(),
+        env: spell.env.clone(),
+        collection_dir: spell.collection_dir.clone(),
+        output_mode: spell.output_mode.clone(),
+        stream_mode: spell.stream_mode,
+        timeout_ms: spell.timeout_ms,
+    })
+}
 
+// The params a spell asks for, so the frontend can show a form before
+// calling apply_spell_with_params. Empty for most spells.
 #[tauri::command]
-fn get_spells(store: tauri::State<'_, SpellStore>) -> Vec<SpellInfo> {
+fn get_spell_params(trigger: String, store: tauri::State<'_, SpellStore>) -> Result<Vec<ParamDef>, String> {
     store.0.lock().unwrap()
         .iter()
-        .map(|s| SpellInfo {
-            trigger: s.trigger.clone(),
-            description: s.description.clone(),
-        })
-        .collect()
+        .find(|s| s.trigger == trigger)
+        .map(|s| s.params.clone())
+        .ok_or_else(|| format!("Spell '{}' not found", trigger))
 }
 
+// Async so the spell runs off the main thread: the window keeps rendering
+// spell-running / spell-progress events while a slow spell works. A spell
+// marked confirm returns NeedsConfirm until it is called again with
+// confirmed set (or through confirm_spell); the captured selection is kept
+// in between, so the confirmed run sees the same input.
 #[tauri::command]
-fn refresh_spells(
-    store: tauri::State<'_, SpellStore>,
-    dir: tauri::State<'_, CollectionsDir>,
-) {
-    *store.0.lock().unwrap() = load_collections(&dir.0);
+async fn apply_spell(
+    trigger: String,
+    fuzzy_match: Option<bool>,
+    confirmed: Option<bool>,
+    app: AppHandle,
+) -> Result<SpellResult, SpellError> {
+    run_spell_in_background(app, trigger, fuzzy_match == Some(true), confirmed == Some(true), None).await
 }
 
+// Missing params fall back to their defaults; one with neither is an error.
 #[tauri::command]
-fn cancel(app: AppHandle, prev_window: tauri::State<'_, PrevWindow>) {
-    if let Some(window) = app.get_webview_window("main") {
-        let _ = window.hide();
-    }
-    let prev = *prev_window.0.lock().unwrap();
-    restore_prev_window(prev);
+async fn apply_spell_with_params(
+    trigger: String,
+    params: HashMap<String, String>,
+    app: AppHandle,
+) -> Result<SpellResult, SpellError> {
+    run_spell_in_background(app, trigger, false, false, Some(params)).await
 }
 
+// Runs a spell marked confirm once the user has agreed to it, with the params
+// it was first asked to run with.
 #[tauri::command]
-fn apply_spell(
+async fn confirm_spell(
     trigger: String,
+    params: Option<HashMap<String, String>>,
     app: AppHandle,
-    prev_window: tauri::State<'_, PrevWindow>,
-    store: tauri::State<'_, SpellStore>,
-    selected: tauri::State<'_, SelectedText>,
-) -> Result<SpellResult, String> {
-    let (entry_cmd, collection_dir, output_mode, stream_mode) = {
-        let spells = store.0.lock().unwrap();
-        let spell = spells.iter()
-            .find(|s| s.trigger == trigger)
-            .ok_or_else(|| format!("Spell '{}' not found", trigger))?;
-        (spell.entry_cmd.clone(), spell.collection_dir.clone(), spell.output_mode.clone(), spell.stream_mode)
-    };
+) -> Result<SpellResult, SpellError> {
+    run_spell_in_background(app, trigger, false, true, params).await
+}
+
+async fn run_spell_in_background(
+    app: AppHandle,
+    trigger: String,
+    fuzzy_match: bool,
+    confirmed: bool,
+    params: Option<HashMap<String, String>>,
+) -> Result<SpellResult, SpellError> {
+    tauri::async_runtime::spawn_blocking(move || run_spell(app, &trigger, fuzzy_match, confirmed, params))
+        .await
+        .map_err(|e| SpellError::from(e.to_string()))?
+}
 
-    let input = selected.0.lock().unwrap().clone();
+fn run_spell(
+    app: AppHandle,
+    trigger: &str,
+    fuzzy_match: bool,
+    confirmed: bool,
+    params: Option<HashMap<String, String>>,
+) -> Result<SpellResult, SpellError> {
+    let prev_window = app.state::<PrevWindow>();
+    let selected = app.state::<SelectedInput>();
+    let original_clipboard = app.state::<OriginalClipboard>();
+    let mut spell = find_spell(&app.state::<SpellStore>(), trigger, fuzzy_match)?;
+    if !spell.params.is_empty() {
+        let Some(params) = params else {
+            return Err(format!("Spell '{}' takes params; run it with apply_spell_with_params", spell.trigger).into());
+        };
+        spell.param_values = resolve_params(&spell, &params)?;
+    }
+    if spell.confirm && !confirmed {
+        return Ok(SpellResult::NeedsConfirm { trigger: spell.trigger });
+    }
+    let output_mode = spell.output_mode.clone();
+    if output_mode == OutputMode::File && spell.output_path.is_none() {
+        return Err(format!("Spell '{}' uses file output but has no outputPath", spell.trigger).into());
+    }
+    let Some(guard) = SpellGuard::acquire(&app.state::<RunningSpell>()) else { return Ok(SpellResult::Busy) };
+
+    let input = match spell.input_from {
+        InputFrom::Selection => selected.0.lock().unwrap().clone(),
+        InputFrom::Clipboard => {
+            let clipboard = app.state::<ClipboardInput>().0.lock().unwrap().clone();
+            clipboard.map(|snapshot| snapshot.to_input()).unwrap_or_default()
+        }
+    };
+    // Taken out so a later cancel no longer restores it.
+    let snapshot = original_clipboard.0.lock().unwrap().take();
 
-    if output_mode == OutputMode::Preview && stream_mode {
-        start_spell_preview_stream(entry_cmd, collection_dir, input, app);
+    if output_mode == OutputMode::Preview && spell.stream_mode {
+        start_spell_preview_stream(spell, input, app.clone(), guard);
         return Ok(SpellResult::Stream);
     }
 
-    if output_mode == OutputMode::Paste && stream_mode {
+    // Streamed output is always typed as it arrives, so paste and type behave alike here.
+    if matches!(output_mode, OutputMode::Paste | OutputMode::Type) && spell.stream_mode {
         if let Some(window) = app.get_webview_window("main") {
             let _ = window.hide();
         }
         let prev = *prev_window.0.lock().unwrap();
         restore_prev_window(prev);
-        std::thread::sleep(std::time::Duration::from_millis(50));
-        start_spell_type_stream(entry_cmd, collection_dir, input);
+        std::thread::sleep(std::time::Duration::from_millis(current_config(&app).paste_delay_ms));
+        start_spell_type_stream(spell, input, app.clone(), guard);
         return Ok(SpellResult::Done);
     }
 
-    let output = execute_spell(&entry_cmd, &collection_dir, &input)?;
+    if output_mode == OutputMode::ClipboardAppend && spell.stream_mode {
+        if let Some(window) = app.get_webview_window("main") {
+            let _ = window.hide();
+        }
+        let prev = *prev_window.0.lock().unwrap();
+        restore_prev_window(prev);
+        start_spell_append_stream(spell, input, app.clone(), guard, snapshot);
+        return Ok(SpellResult::Done);
+    }
+
+    let output = run_to_output(&app, &spell, &input)?;
 
     match output_mode {
         OutputMode::None => {
@@ -460,9 +3969,11 @@ fn apply_spell(
             restore_prev_window(prev);
             Ok(SpellResult::Done)
         }
-        OutputMode::Clipboard => {
-            if let Ok(mut clipboard) = arboard::Clipboard::new() {
-                let _ = clipboard.set_text(output.trim_end_matches('\n'));
+        OutputMode::Clipboard | OutputMode::ClipboardAppend => {
+            if output_mode == OutputMode::ClipboardAppend {
+                append_clipboard_output(&spell, snapshot.as_deref(), &output);
+            } else {
+                set_clipboard_output(&spell, &output);
             }
             if let Some(window) = app.get_webview_window("main") {
                 let _ = window.hide();
@@ -472,111 +3983,448 @@ fn apply_spell(
             Ok(SpellResult::Done)
         }
         OutputMode::Preview => {
-            Ok(SpellResult::Preview { content: output })
+            Ok(SpellResult::Preview { content: output, format: spell.preview_format })
         }
-        OutputMode::Paste => {
+        OutputMode::Paste | OutputMode::Html => {
+            // Fails before anything is hidden or overwritten when input can't be simulated.
+            with_input_simulator(&app, |_| ())?;
+            // Prefer what the user had copied before the shortcut fired; by now the
+            // clipboard usually just holds the selection from the simulated copy.
+            let mut original = None;
             if let Ok(mut clipboard) = arboard::Clipboard::new() {
-                let _ = clipboard.set_text(output.trim_end_matches('\n'));
+                if spell.preserve_clipboard.unwrap_or(current_config(&app).restore_clipboard) {
+                    original = snapshot.or_else(|| ClipboardSnapshot::take(&mut clipboard).map(Arc::new));
+                }
+                let output = trim_line_ending(&output);
+                if output_mode == OutputMode::Html {
+                    let _ = clipboard.set_html(output, Some(strip_html_tags(output).as_str()));
+                } else {
+                    let _ = clipboard.set_text(combine_with_selection(
+                        spell.paste_mode,
+                        spell.paste_separator.as_deref(),
+                        input.text(),
+                        output,
+                    ));
+                }
+            }
+            if let Some(window) = app.get_webview_window("main") {
+                let _ = window.hide();
+            }
+            let prev = *prev_window.0.lock().unwrap();
+            restore_prev_window(prev);
+            std::thread::sleep(std::time::Duration::from_millis(current_config(&app).paste_delay_ms));
+            with_input_simulator(&app, simulate_paste)?;
+            if let Some(original) = original {
+                restore_clipboard_later(original, current_config(&app).clipboard_restore_delay_ms);
+            }
+            Ok(SpellResult::Done)
+        }
+        OutputMode::File => {
+            write_output_file(&spell, &output)?;
+            if let Some(window) = app.get_webview_window("main") {
+                let _ = window.hide();
+            }
+            let prev = *prev_window.0.lock().unwrap();
+            restore_prev_window(prev);
+            Ok(SpellResult::Done)
+        }
+        OutputMode::Notify => {
+            notify_output(&app, &spell.trigger, &output);
+            if let Some(window) = app.get_webview_window("main") {
+                let _ = window.hide();
             }
+            let prev = *prev_window.0.lock().unwrap();
+            restore_prev_window(prev);
+            Ok(SpellResult::Done)
+        }
+        OutputMode::Type => {
+            with_input_simulator(&app, |_| ())?;
             if let Some(window) = app.get_webview_window("main") {
                 let _ = window.hide();
             }
             let prev = *prev_window.0.lock().unwrap();
             restore_prev_window(prev);
-            std::thread::sleep(std::time::Duration::from_millis(50));
-            if let Ok(mut enigo) = Enigo::new(&Settings::default()) {
-                simulate_paste(&mut enigo);
+            std::thread::sleep(std::time::Duration::from_millis(current_config(&app).paste_delay_ms));
+            let delay_ms = spell.type_delay_ms;
+            with_input_simulator(&app, move |enigo| type_text(enigo, trim_line_ending(&output), delay_ms, None))?;
+            Ok(SpellResult::Done)
+        }
+        OutputMode::Append | OutputMode::Prepend => {
+            with_input_simulator(&app, |_| ())?;
+            if let Some(window) = app.get_webview_window("main") {
+                let _ = window.hide();
             }
+            let prev = *prev_window.0.lock().unwrap();
+            restore_prev_window(prev);
+            std::thread::sleep(std::time::Duration::from_millis(current_config(&app).paste_delay_ms));
+            // With nothing selected the caret is already where the output belongs.
+            let collapse = !input.is_empty();
+            let to_end = output_mode == OutputMode::Append;
+            let delay_ms = spell.type_delay_ms;
+            with_input_simulator(&app, move |enigo| {
+                if collapse {
+                    collapse_selection(enigo, to_end);
+                }
+                type_text(enigo, trim_line_ending(&output), delay_ms, None);
+            })?;
             Ok(SpellResult::Done)
         }
     }
 }
 
+// Applies an edit to the collections directory list, persists it to
+// config.json, updates the watcher and reloads spells from the new set.
+fn update_collections_dirs(
+    app: &AppHandle,
+    edit: impl FnOnce(&mut Vec<PathBuf>) -> Result<(), String>,
+) -> Result<(), String> {
+    use notify::Watcher;
+
+    let (old_dirs, dirs) = {
+        let state = app.state::<CollectionsDirs>();
+        let mut dirs = state.0.lock().unwrap();
+        let old_dirs = dirs.clone();
+        edit(&mut dirs)?;
+        (old_dirs, dirs.clone())
+    };
+    if let Some(watcher) = app.state::<WatcherHandle>().0.lock().unwrap().as_mut() {
+        for dir in old_dirs.iter().filter(|dir| !dirs.contains(dir)) {
+            let _ = watcher.unwatch(dir);
+        }
+        for dir in dirs.iter().filter(|dir| !old_dirs.contains(dir)) {
+            watch_dir(watcher, dir);
+        }
+    }
+    {
+        let config = app.state::<ConfigStore>();
+        let mut config = config.0.lock().unwrap();
+        config.collections_dirs = dirs;
+        save_config(&get_config_path(), &config)?;
+    }
+    reload_spells(app);
+    Ok(())
+}
+
+#[tauri::command]
+fn add_collections_dir(path: String, app: AppHandle) -> Result<(), String> {
+    let path = PathBuf::from(path);
+    if !path.is_dir() {
+        return Err(format!("'{}' is not a directory", path.display()));
+    }
+    update_collections_dirs(&app, |dirs| {
+        if !dirs.contains(&path) {
+            dirs.push(path);
+        }
+        Ok(())
+    })
+}
+
+#[tauri::command]
+fn remove_collections_dir(path: String, app: AppHandle) -> Result<(), String> {
+    let path = PathBuf::from(path);
+    update_collections_dirs(&app, |dirs| {
+        let index = dirs.iter()
+            .position(|dir| *dir == path)
+            .ok_or_else(|| format!("'{}' is not a collections directory", path.display()))?;
+        dirs.remove(index);
+        Ok(())
+    })
+}
+
+// Downloads the index.json at url into the cache and adds the URL to
+// config.json. Async so the download doesn't block the main thread.
+#[tauri::command]
+async fn add_remote_collection(url: String, app: AppHandle) -> Result<(), String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let url = url.trim().to_string();
+        let body = fetch_remote_index(&url)?;
+        write_remote_cache(&remote_cache_path(&url), &body)?;
+        {
+            let config = app.state::<ConfigStore>();
+            let mut config = config.0.lock().unwrap();
+            if !config.remote_collections.contains(&url) {
+                config.remote_collections.push(url);
+            }
+            save_config(&get_config_path(), &config)?;
+        }
+        reload_spells(&app);
+        Ok(())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+// Writes a collection folder (index file, scripts and all) to a ZIP archive.
+#[tauri::command]
+fn export_collection(collection_dir: String, dest_path: String) -> Result<(), String> {
+    let dir = PathBuf::from(collection_dir);
+    let dest = PathBuf::from(dest_path);
+    if find_index_file(&dir).is_none() {
+        return Err(format!("{} is not a collection folder", dir.display()));
+    }
+    if dest.starts_with(&dir) {
+        return Err("the archive can't be written inside the collection it exports".to_string());
+    }
+    let file = std::fs::File::create(&dest).map_err(|e| format!("{}: {}", dest.display(), e))?;
+    let mut zip = zip::ZipWriter::new(file);
+    add_dir_to_zip(&mut zip, &dir, &dir)?;
+    zip.finish().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Unpacks an archive into a new folder of the primary collections directory,
+// named after the archive (with -2, -3, ... if taken). The folder is removed
+// again if its index file doesn't parse.
+#[tauri::command]
+fn import_collection(zip_path: String, app: AppHandle) -> Result<(), String> {
+    let zip_path = PathBuf::from(zip_path);
+    let file = std::fs::File::open(&zip_path).map_err(|e| format!("{}: {}", zip_path.display(), e))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| format!("{}: {}", zip_path.display(), e))?;
+    let names = archive_entry_names(&mut archive)?;
+    let root = archive_root(&names)
+        .ok_or_else(|| format!("{} has no index file", zip_path.display()))?;
+
+    let parent = app.state::<CollectionsDirs>().0.lock().unwrap()
+        .first()
+        .cloned()
+        .ok_or("no collections directory is configured")?;
+    let name = root.file_name()
+        .or_else(|| zip_path.file_stem())
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "imported".to_string());
+    let mut target = parent.join(&name);
+    let mut suffix = 2;
+    while target.exists() {
+        target = parent.join(format!("{}-{}", name, suffix));
+        suffix += 1;
+    }
+
+    let result = extract_archive(&mut archive, &names, &root, &target).and_then(|()| {
+        let index_path = find_index_file(&target).ok_or("the archive has no index file")?;
+        validate_collection(&index_path).map(|_| ())
+    });
+    if let Err(e) = result {
+        let _ = std::fs::remove_dir_all(&target);
+        return Err(e);
+    }
+    reload_spells(&app);
+    Ok(())
+}
+
+#[tauri::command]
+fn get_app_config(config: tauri::State<'_, ConfigStore>) -> AppConfig {
+    config.0.lock().unwrap().clone()
+}
+
+// Replaces the whole config. The hotkey and collections directories go through
+// their usual update paths so the shortcut and watcher follow the new values.
+#[tauri::command]
+fn set_app_config(config: AppConfig, app: AppHandle) -> Result<(), String> {
+    update_hotkey(&app, config.hotkey.clone())?;
+    let collections_dirs = config.collections_dirs.clone();
+    update_collections_dirs(&app, |dirs| {
+        *dirs = collections_dirs;
+        Ok(())
+    })?;
+    let store = app.state::<ConfigStore>();
+    let mut current = store.0.lock().unwrap();
+    *current = config;
+    save_config(&get_config_path(), &current)
+}
+
+// Swaps the registered activation shortcut and persists it. On any failure the
+// previous shortcut stays registered.
+fn update_hotkey(app: &AppHandle, hotkey: HotkeyConfig) -> Result<(), String> {
+    let shortcut = parse_hotkey(&hotkey)?;
+
+    // Register the replacement before dropping the old one so a failure
+    // (e.g. the combo is taken by another app) leaves the current hotkey working.
+    let active = app.state::<ActiveShortcut>();
+    let mut current = active.0.lock().unwrap();
+    if shortcut != *current {
+        app.global_shortcut()
+            .on_shortcut(shortcut, |app, _shortcut, event| on_activate(app, event))
+            .map_err(|e| e.to_string())?;
+        let _ = app.global_shortcut().unregister(*current);
+        *current = shortcut;
+    }
+
+    let config = app.state::<ConfigStore>();
+    let mut config = config.0.lock().unwrap();
+    config.hotkey = hotkey;
+    save_config(&get_config_path(), &config)
+}
+
+#[tauri::command]
+fn set_hotkey(modifiers: Vec<String>, code: String, app: AppHandle) -> Result<(), String> {
+    update_hotkey(&app, HotkeyConfig { modifiers, code })
+}
+
+#[tauri::command]
+fn get_shortcut(config: tauri::State<'_, ConfigStore>) -> String {
+    config.0.lock().unwrap().hotkey.to_string()
+}
+
+#[tauri::command]
+fn set_shortcut(shortcut: String, app: AppHandle) -> Result<(), String> {
+    update_hotkey(&app, HotkeyRepr::Text(shortcut).into())
+}
+
 // ---- Entry point ----
 
+// The login item (macOS), Run key (Windows) or autostart entry (Linux) is
+// itself the saved setting, so the checkmark is always read back from it.
+fn toggle_autostart(app: &AppHandle, item: &tauri::menu::CheckMenuItem<tauri::Wry>) {
+    use tauri_plugin_autostart::ManagerExt;
+
+    let autolaunch = app.autolaunch();
+    let enabled = autolaunch.is_enabled().unwrap_or(false);
+    let result = if enabled { autolaunch.disable() } else { autolaunch.enable() };
+    if let Err(e) = result {
+        log_warning(format!("could not change launch at login ({e})"));
+    }
+    let _ = item.set_checked(autolaunch.is_enabled().unwrap_or(enabled));
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    use tauri::menu::{Menu, MenuItem};
+    use tauri::menu::{CheckMenuItem, Menu, MenuItem};
+    use tauri_plugin_autostart::{MacosLauncher, ManagerExt as _};
     use tauri::tray::TrayIconBuilder;
 
-    let collections_dir = get_collections_dir();
-    ensure_collections_dir(&collections_dir);
-    let initial_spells = load_collections(&collections_dir);
+    let config = load_config(&get_config_path());
+    let collections_dirs = config.collections_dirs.clone();
+    if collections_dirs.contains(&get_collections_dir()) {
+        ensure_collections_dir(&get_collections_dir());
+    }
+    // Remote collections load from their cache here and are refetched once the app is up.
+    let loaded = load_collections(&collection_sources(&collections_dirs, &config.remote_collections), config.strict_mode)
+        .unwrap_or_else(|errors| LoadResult {
+            collections: Vec::new(),
+            errors,
+            diagnostics: Vec::new(),
+            stats: LoadStats::default(),
+        });
+    let (watcher, watch_events) = match collections_watcher() {
+        Ok((watcher, rx)) => (Some(watcher), Some(rx)),
+        Err(e) => {
+            log_warning(format!("could not watch collections ({e}), use Refresh Spells instead"));
+            (None, None)
+        }
+    };
+    let shortcut = parse_hotkey(&config.hotkey).unwrap_or_else(|e| {
+        log_warning(format!("invalid hotkey in config ({e}), falling back to Ctrl+Space"));
+        default_shortcut()
+    });
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_global_shortcut::Builder::new().build())
+        .plugin(tauri_plugin_notification::init())
+        .plugin(tauri_plugin_autostart::init(MacosLauncher::AppleScript, None))
         .manage(PrevWindow(Mutex::new(0)))
-        .manage(SpellStore(Mutex::new(initial_spells)))
-        .manage(CollectionsDir(collections_dir))
-        .manage(SelectedText(Mutex::new(String::new())))
-        .setup(|app| {
+        .manage(SpellStore(Mutex::new(all_spells(&loaded.collections))))
+        .manage(CollectionStore(Mutex::new(loaded.collections)))
+        .manage(LoadErrors(Mutex::new(loaded.errors)))
+        .manage(LoadDiagnostics(Mutex::new(loaded.diagnostics)))
+        .manage(LastLoadStats(Mutex::new(loaded.stats)))
+        .manage(CollectionsDirs(Mutex::new(collections_dirs)))
+        .manage(SelectedInput(Mutex::new(SpellInput::default())))
+        .manage(ClipboardInput(Mutex::new(None)))
+        .manage(OriginalClipboard(Mutex::new(None)))
+        .manage(ConfigStore(Mutex::new(config)))
+        .manage(ActiveShortcut(Mutex::new(shortcut)))
+        .manage(StatsStore(Mutex::new(load_stats(&get_stats_path()))))
+        .manage(Favorites(Mutex::new(load_favorites(&get_favorites_path()))))
+        .manage(Paused(Mutex::new(false)))
+        .manage(ActiveStream(Mutex::new(None)))
+        .manage(RunningProcesses(Mutex::new(HashMap::new())))
+        .manage(RunningSpell(Arc::new(AtomicBool::new(false))))
+        .manage(WatcherHandle(Mutex::new(watcher)))
+        .on_window_event(|window, event| {
+            if let tauri::WindowEvent::Focused(false) = event {
+                if window.label() == "main" {
+                    on_palette_blur(window);
+                }
+            }
+        })
+        .setup(move |app| {
             #[cfg(target_os = "macos")]
             app.set_activation_policy(tauri::ActivationPolicy::Accessory);
 
-            let shortcut = Shortcut::new(Some(Modifiers::CONTROL), Code::Space);
-            app.global_shortcut().on_shortcut(shortcut, |app, _shortcut, event| {
-                if event.state != ShortcutState::Pressed { return; }
-
-                if let Some(state) = app.try_state::<PrevWindow>() {
-                    save_prev_window(&state);
-                }
-
-                let gen_before = clipboard_generation();
+            #[cfg(target_os = "linux")]
+            linux::start_clipboard_watcher();
 
-                if let Ok(mut enigo) = Enigo::new(&Settings::default()) {
-                    simulate_copy(&mut enigo);
-                }
+            app.global_shortcut()
+                .on_shortcut(shortcut, |app, _shortcut, event| on_activate(app, event))?;
 
-                std::thread::sleep(std::time::Duration::from_millis(100));
-
-                // The clipboard generation counter increments on every clipboard update,
-                // even when the new content is identical to the old content. This correctly
-                // handles two edge cases that a simple before/after content comparison fails:
-                //   - Selecting the same text twice: counter increments → detected as selection.
-                //   - Selecting nothing: Ctrl+C has no effect → counter unchanged → empty input.
-                let selected = if clipboard_generation() != gen_before {
-                    arboard::Clipboard::new()
-                        .and_then(|mut c| c.get_text())
-                        .unwrap_or_default()
-                } else {
-                    String::new()
-                };
-                if let Some(state) = app.try_state::<SelectedText>() {
-                    *state.0.lock().unwrap() = selected;
-                }
+            // Recursive watches walk every subdirectory up front (inotify has no
+            // recursive mode), so set them up off the main thread. Holding the
+            // lock meanwhile makes a directory added in the meantime wait its turn.
+            if let Some(rx) = watch_events {
+                let handle = app.handle().clone();
+                std::thread::spawn(move || {
+                    if let Some(watcher) = handle.state::<WatcherHandle>().0.lock().unwrap().as_mut() {
+                        for dir in handle.state::<CollectionsDirs>().0.lock().unwrap().iter() {
+                            watch_dir(watcher, dir);
+                        }
+                    }
+                    run_collections_watcher(&handle, rx);
+                });
+            }
 
-                if let Some(window) = app.get_webview_window("main") {
-                    let _ = window.show();
-                    let _ = window.set_focus();
-                }
-            })?;
+            let handle = app.handle().clone();
+            std::thread::spawn(move || refresh_remote_collections(&handle));
 
             let refresh_item = MenuItem::with_id(app, "refresh", "Refresh Spells", true, None::<&str>)?;
             let open_item = MenuItem::with_id(app, "open_collections", "Open Collections Folder", true, None::<&str>)?;
+            let autostart_item = CheckMenuItem::with_id(
+                app,
+                "autostart",
+                "Launch at Login",
+                true,
+                app.autolaunch().is_enabled().unwrap_or(false),
+                None::<&str>,
+            )?;
+            let pause_item = CheckMenuItem::with_id(app, "pause", "Pause Shortcut", true, false, None::<&str>)?;
+            let stop_item = MenuItem::with_id(app, "stop_stream", "Stop Running Spell", true, None::<&str>)?;
             let quit_item = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
-            let menu = Menu::with_items(app, &[&refresh_item, &open_item, &quit_item])?;
+            let menu = Menu::with_items(
+                app,
+                &[&refresh_item, &open_item, &stop_item, &pause_item, &autostart_item, &quit_item],
+            )?;
 
             TrayIconBuilder::new()
                 .icon(app.default_window_icon().unwrap().clone())
                 .menu(&menu)
-                .on_menu_event(|app, event| match event.id.as_ref() {
-                    "refresh" => {
-                        if let (Some(store), Some(dir)) = (
-                            app.try_state::<SpellStore>(),
-                            app.try_state::<CollectionsDir>(),
-                        ) {
-                            *store.0.lock().unwrap() = load_collections(&dir.0);
+                .on_menu_event(move |app, event| match event.id.as_ref() {
+                    "refresh" => reload_spells(app),
+                    // Opens the primary (first) directory; the others are usually
+                    // shared folders managed elsewhere.
+                    "open_collections" => {
+                        if let Some(dirs) = app.try_state::<CollectionsDirs>() {
+                            // The opener plugin picks open, explorer or xdg-open for us.
+                            if let Some(dir) = dirs.0.lock().unwrap().first() {
+                                if let Err(e) = app.opener().open_path(dir.to_string_lossy(), None::<&str>) {
+                                    log_warning(format!("could not open {} ({e})", dir.display()));
+                                }
+                            }
                         }
                     }
-                    "open_collections" => {
-                        if let Some(dir) = app.try_state::<CollectionsDir>() {
-                            #[cfg(target_os = "macos")]
-                            let _ = std::process::Command::new("open").arg(&dir.0).spawn();
-                            #[cfg(target_os = "windows")]
-                            let _ = std::process::Command::new("explorer").arg(&dir.0).spawn();
+                    "pause" => {
+                        let paused = app.state::<Paused>();
+                        let mut paused = paused.0.lock().unwrap();
+                        *paused = !*paused;
+                        let _ = pause_item.set_checked(*paused);
+                    }
+                    // The palette is hidden while a stream types, so this is the
+                    // way to stop one that is typing into the wrong place.
+                    "stop_stream" => {
+                        if let Some(handle) = app.state::<ActiveStream>().0.lock().unwrap().take() {
+                            handle.cancel();
                         }
                     }
+                    "autostart" => toggle_autostart(app, &autostart_item),
                     "quit" => app.exit(0),
                     _ => {}
                 })
@@ -584,7 +4432,462 @@ pub fn run() {
 
             Ok(())
         })
-        .invoke_handler(tauri::generate_handler![get_spells, apply_spell, refresh_spells, cancel])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .invoke_handler(tauri::generate_handler![
+            get_spells,
+            get_spell_categories,
+            get_spells_by_category,
+            search_spells,
+            get_recent_spells,
+            get_favorite_spells,
+            toggle_favorite,
+            get_spell_stats,
+            reset_spell_stats,
+            get_collections,
+            get_load_errors,
+            get_load_diagnostics,
+            get_load_warnings,
+            get_load_stats,
+            describe_spell,
+            get_spell_params,
+            apply_spell,
+            apply_spell_with_params,
+            confirm_spell,
+            run_spell_with_input,
+            test_spell,
+            refresh_spells,
+            set_spell_enabled,
+            create_spell,
+            cancel,
+            cancel_stream,
+            cancel_spell,
+            set_hotkey,
+            get_shortcut,
+            set_shortcut,
+            add_collections_dir,
+            remove_collections_dir,
+            add_remote_collection,
+            export_collection,
+            import_collection,
+            get_app_config,
+            set_app_config,
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            // Stop watching before the runtime tears down so the reload thread
+            // doesn't race against state being dropped.
+            if let tauri::RunEvent::Exit = event {
+                if let Some(watcher) = app.try_state::<WatcherHandle>() {
+                    watcher.0.lock().unwrap().take();
+                }
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh folder under the temp directory, removed when dropped.
+    struct ScratchDir(PathBuf);
+
+    impl ScratchDir {
+        fn new(name: &str) -> ScratchDir {
+            let dir = std::env::temp_dir().join(format!("spellpaste-test-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            ScratchDir(dir)
+        }
+
+        // Writes an index.json with one spell per (trigger, command) into dir/folder.
+        fn collection(&self, folder: &str, order: Option<i64>, spells: &[(&str, &str)]) -> PathBuf {
+            let dir = self.0.join(folder);
+            std::fs::create_dir_all(&dir).unwrap();
+            let spells: Vec<_> = spells.iter()
+                .map(|(trigger, cmd)| serde_json::json!({ "trigger": trigger, "entry": { "default": cmd } }))
+                .collect();
+            let mut index = serde_json::json!({ "name": folder, "spells": spells });
+            if let Some(order) = order {
+                index["order"] = order.into();
+            }
+            std::fs::write(dir.join("index.json"), index.to_string()).unwrap();
+            dir
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn load(dirs: &[&ScratchDir]) -> LoadResult {
+        let dirs: Vec<PathBuf> = dirs.iter().map(|d| d.0.clone()).collect();
+        load_collections(&collection_sources(&dirs, &[]), false).ok().unwrap()
+    }
+
+    fn triggers(loaded: &LoadResult) -> Vec<String> {
+        all_spells(&loaded.collections).into_iter().map(|s| s.trigger).collect()
+    }
+
+    // Loads a single spell from its index entry. The folder has to outlive the
+    // spell, which runs in it.
+    fn one_spell(name: &str, def: serde_json::Value) -> (ScratchDir, LoadedSpell) {
+        let dir = ScratchDir::new(name);
+        let folder = dir.0.join("spells");
+        std::fs::create_dir_all(&folder).unwrap();
+        let index = serde_json::json!({ "spells": [def] });
+        std::fs::write(folder.join("index.json"), index.to_string()).unwrap();
+        let spell = all_spells(&load(&[&dir]).collections).remove(0);
+        (dir, spell)
+    }
+
+    fn run(spell: &LoadedSpell, input: &str) -> Result<String, SpellError> {
+        execute_spell(spell, &SpellInput::Text(input.to_string()), None, |_| {})
+    }
+
+    #[test]
+    fn spells_from_two_directories_are_merged() {
+        let first = ScratchDir::new("merge-a");
+        let second = ScratchDir::new("merge-b");
+        first.collection("text", None, &[("upper", "tr a-z A-Z")]);
+        second.collection("dev", None, &[("lower", "tr A-Z a-z")]);
+        let loaded = load(&[&first, &second]);
+        assert_eq!(triggers(&loaded), ["upper", "lower"]);
+        assert!(loaded.errors.is_empty());
+    }
+
+    #[test]
+    fn earlier_directory_wins_a_clash_whatever_the_order() {
+        let first = ScratchDir::new("clash-a");
+        let second = ScratchDir::new("clash-b");
+        first.collection("mine", None, &[("hello", "echo first")]);
+        second.collection("theirs", Some(0), &[("hello", "echo second")]);
+        let loaded = load(&[&first, &second]);
+        let spells = all_spells(&loaded.collections);
+        let hello = spells.iter().find(|s| s.trigger == "hello").unwrap();
+        assert_eq!(hello.entry_cmd, "echo first");
+        assert_eq!(loaded.diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn a_trigger_repeated_in_one_collection_is_reported() {
+        let dir = ScratchDir::new("repeat");
+        dir.collection("text", None, &[("hello", "echo first"), ("hello", "echo second")]);
+        let loaded = load(&[&dir]);
+        let spells = all_spells(&loaded.collections);
+        assert_eq!(spells.len(), 1);
+        assert_eq!(spells[0].entry_cmd, "echo first");
+        assert_eq!(loaded.errors.len(), 1);
+        assert!(loaded.errors[0].1.contains("'hello'"));
+    }
+
+    #[test]
+    fn disabling_a_json_spell_keeps_key_order() {
+        let dir = ScratchDir::new("enable-json");
+        let index = dir.0.join("index.json");
+        let content = r#"{"spells": [{"trigger": "a", "entry": {"default": "echo a"}},
+            {"trigger": "b", "description": "say \"hi\"", "entry": {"default": "echo b"}}], "name": "text"}"#;
+        std::fs::write(&index, content).unwrap();
+        write_spell_enabled(&index, 1, false).unwrap();
+        let written = std::fs::read_to_string(&index).unwrap();
+        let doc: serde_json::Value = serde_json::from_str(&written).unwrap();
+        let keys: Vec<&String> = doc["spells"][1].as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["trigger", "description", "entry", "enabled"]);
+        assert_eq!(doc["spells"][1]["description"], "say \"hi\"");
+        assert_eq!(doc["spells"][0].get("enabled"), None);
+        assert!(written.find("\"spells\"") < written.find("\"name\""));
+    }
+
+    #[test]
+    fn disabling_a_yaml_spell_keeps_comments() {
+        let dir = ScratchDir::new("enable-yaml");
+        let index = dir.0.join("index.yaml");
+        let content = "# my spells\nspells:\n  - trigger: a # first\n    entry:\n      default: echo a\n\n  # the second one\n  - trigger: b\n    entry: { default: echo b }\n";
+        std::fs::write(&index, content).unwrap();
+        write_spell_enabled(&index, 0, false).unwrap();
+        let disabled = std::fs::read_to_string(&index).unwrap();
+        assert_eq!(disabled, content.replace("echo a\n", "echo a\n    enabled: false\n"));
+        write_spell_enabled(&index, 0, true).unwrap();
+        let enabled = std::fs::read_to_string(&index).unwrap();
+        assert_eq!(enabled, disabled.replace("enabled: false", "enabled: true"));
+    }
+
+    #[test]
+    fn enabling_a_yaml_spell_keeps_the_comment_on_its_line() {
+        let dir = ScratchDir::new("enable-yaml-existing");
+        let index = dir.0.join("index.yaml");
+        let content = "spells:\n  - trigger: \"say \\\"hi\\\"\"\n    enabled: false # for now\n    entry:\n      default: echo hi\n";
+        std::fs::write(&index, content).unwrap();
+        write_spell_enabled(&index, 0, true).unwrap();
+        let enabled = std::fs::read_to_string(&index).unwrap();
+        assert_eq!(enabled, content.replace("enabled: false", "enabled: true"));
+    }
+
+    #[test]
+    fn yaml_layouts_that_cant_be_edited_in_place_are_left_alone() {
+        let dir = ScratchDir::new("enable-yaml-unsupported");
+        let index = dir.0.join("index.yaml");
+        let layouts = [
+            "spells: [{ trigger: a, entry: { default: echo a } }]\n",
+            "spells:\n  - { trigger: a, entry: { default: echo a } }\n",
+            "spells:\n  - trigger: a\n    entry: { default: echo a }\n---\nspells: []\n",
+        ];
+        for content in layouts {
+            std::fs::write(&index, content).unwrap();
+            assert!(write_spell_enabled(&index, 0, false).is_err(), "{}", content);
+            assert_eq!(std::fs::read_to_string(&index).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn disabled_spells_are_not_loaded() {
+        let dir = ScratchDir::new("disabled");
+        let folder = dir.collection("text", None, &[("upper", "tr a-z A-Z"), ("lower", "tr A-Z a-z")]);
+        write_spell_enabled(&folder.join("index.json"), 1, false).unwrap();
+        let loaded = load(&[&dir]);
+        assert_eq!(triggers(&loaded), ["upper"]);
+        assert_eq!(loaded.collections[0].disabled[0].trigger, "lower");
+    }
+
+    #[test]
+    fn a_hung_hook_is_killed_at_the_spell_timeout() {
+        let dir = ScratchDir::new("hook-timeout");
+        let folder = dir.0.join("slow");
+        std::fs::create_dir_all(&folder).unwrap();
+        let index = serde_json::json!({ "spells": [{
+            "trigger": "slow",
+            "entry": { "default": "cat" },
+            "after": "sleep 5",
+            "settings": { "timeoutMs": 200 },
+        }] });
+        std::fs::write(folder.join("index.json"), index.to_string()).unwrap();
+        let spell = all_spells(&load(&[&dir]).collections).remove(0);
+        let started = std::time::Instant::now();
+        let result = run_hook(&spell, "after", spell.after_hook.as_deref().unwrap(), &SpellInput::Text(String::new()));
+        assert!(result.unwrap_err().contains("timed out after 200 ms"));
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+    }
+
+    #[test]
+    fn renaming_a_collection_folder_triggers_a_reload() {
+        use notify::event::{ModifyKind, RenameMode};
+
+        let dirs = vec![PathBuf::from("/spells")];
+        let renamed = notify::Event::new(notify::EventKind::Modify(ModifyKind::Name(RenameMode::To)))
+            .add_path(PathBuf::from("/spells/tools"));
+        assert!(is_index_event(&renamed, &dirs));
+        let other = notify::Event::new(notify::EventKind::Modify(ModifyKind::Name(RenameMode::To)))
+            .add_path(PathBuf::from("/spells/tools/notes.txt"));
+        assert!(!is_index_event(&other, &dirs));
+    }
+
+    #[test]
+    fn same_named_folders_in_different_directories_get_distinct_ids() {
+        let dirs: Vec<_> = ["prefix-a", "prefix-b", "prefix-c"].iter().map(|name| ScratchDir::new(name)).collect();
+        for dir in &dirs {
+            dir.collection("tools", None, &[("hello", "echo hi")]);
+        }
+        let loaded = load(&dirs.iter().collect::<Vec<_>>());
+        assert_eq!(triggers(&loaded), ["hello", "1:tools/hello", "2:tools/hello"]);
+    }
+
+    #[test]
+    fn same_named_nested_folders_get_distinct_ids() {
+        let dir = ScratchDir::new("nested");
+        for group in ["a", "b", "c"] {
+            dir.collection(&format!("{group}/tools"), None, &[("hello", "echo hi")]);
+        }
+        let loaded = load(&[&dir]);
+        assert_eq!(triggers(&loaded), ["hello", "0:b/tools/hello", "0:c/tools/hello"]);
+        assert_eq!(loaded.collections[0].dir, dir.0.join("a/tools"));
+    }
+
+    #[test]
+    fn shadowed_aliases_are_prefixed_like_their_entries() {
+        let first = ScratchDir::new("alias-a");
+        let second = ScratchDir::new("alias-b");
+        for dir in [&first, &second] {
+            let folder = dir.0.join("tools");
+            std::fs::create_dir_all(&folder).unwrap();
+            let index = serde_json::json!({ "spells": [{
+                "trigger": "timestamp",
+                "aliases": ["ts"],
+                "entry": { "default": "date" },
+            }] });
+            std::fs::write(folder.join("index.json"), index.to_string()).unwrap();
+        }
+        let spells = all_spells(&load(&[&first, &second]).collections);
+        let shadowed = spells.iter().find(|s| s.trigger == "1:tools/timestamp").unwrap();
+        assert_eq!(shadowed.aliases, ["1:tools/ts"]);
+        assert!(spells.iter().any(|s| s.trigger == "1:tools/ts"));
+    }
+
+    // Sends `chunks` chunks of "x" 10ms apart, then closes the channel.
+    fn trickle(chunks: usize) -> std::sync::mpsc::Receiver<String> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for _ in 0..chunks {
+                let _ = tx.send("x".to_string());
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        });
+        rx
+    }
+
+    fn count_flushes(flush_ms: u64) -> (usize, String) {
+        let mut flushes = 0;
+        let mut output = String::new();
+        let end = stream_batched(trickle(60), None, std::time::Duration::from_millis(flush_ms), &AtomicBool::new(false), |chunk, _| {
+            flushes += 1;
+            output.push_str(chunk);
+        });
+        assert!(matches!(end, StreamEnd::Finished));
+        (flushes, output)
+    }
+
+    #[test]
+    fn a_smaller_flush_interval_flushes_more_often() {
+        let (often, often_output) = count_flushes(MIN_STREAM_FLUSH_MS);
+        let (rarely, rarely_output) = count_flushes(400);
+        assert!(often > rarely, "{often} flushes at {MIN_STREAM_FLUSH_MS}ms vs {rarely} at 400ms");
+        assert_eq!(often_output, "x".repeat(60));
+        assert_eq!(rarely_output, often_output);
+    }
+
+    // Hands out the given byte slices one read at a time.
+    struct ChunkedReader(std::collections::VecDeque<Vec<u8>>);
+
+    impl std::io::Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let Some(chunk) = self.0.pop_front() else { return Ok(0) };
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn a_character_split_across_reads_comes_through_whole() {
+        let bytes = "caf\u{e9} \u{1f600}".as_bytes();
+        // Splits inside both the two-byte é and the four-byte emoji.
+        let reads = [&bytes[..4], &bytes[4..7], &bytes[7..]].map(|r| r.to_vec());
+        let rx = pipe_to_channel(ChunkedReader(reads.into_iter().collect()));
+        let chunks: Vec<String> = rx.iter().collect();
+        assert_eq!(chunks.concat(), "caf\u{e9} \u{1f600}");
+        assert!(chunks.iter().all(|c| !c.contains('\u{fffd}')));
+    }
+
+    #[test]
+    fn a_failing_spell_is_an_error() {
+        let (_dir, spell) = one_spell("exit-1", serde_json::json!({
+            "trigger": "fail",
+            "entry": { "default": "echo partial; exit 1" },
+            "settings": { "outputMode": "clipboard" },
+        }));
+        match run(&spell, "") {
+            Err(SpellError::ExecutionFailed { code, stdout, .. }) => {
+                assert_eq!(code, Some(1));
+                assert_eq!(stdout.trim(), "partial");
+            }
+            other => panic!("expected ExecutionFailed, got {:?}", other.map_err(|e| e.to_string())),
+        }
+    }
+
+    #[test]
+    fn env_vars_reach_the_spell() {
+        let (_dir, spell) = one_spell("env", serde_json::json!({
+            "trigger": "foo",
+            "entry": { "default": "echo $FOO", "env": { "FOO": "bar" } },
+        }));
+        assert_eq!(run(&spell, "").ok().as_deref(), Some("bar\n"));
+    }
+
+    #[test]
+    fn a_yaml_index_loads_the_same_spells_as_json() {
+        let dir = ScratchDir::new("yaml-json");
+        let json = dir.0.join("json");
+        let yaml = dir.0.join("yaml");
+        std::fs::create_dir_all(&json).unwrap();
+        std::fs::create_dir_all(&yaml).unwrap();
+        std::fs::write(json.join("index.json"), r#"{
+            "name": "text",
+            "spells": [
+                {
+                    "trigger": "upper",
+                    "description": "Uppercase",
+                    "category": "text",
+                    "aliases": ["up"],
+                    "entry": { "default": "tr a-z A-Z", "env": { "LC_ALL": "C" }, "args": ["-v"] },
+                    "settings": { "outputMode": "clipboard", "timeoutMs": 500 },
+                    "priority": 2
+                },
+                { "trigger": "lower", "entry": { "default": "tr A-Z a-z" } }
+            ]
+        }"#).unwrap();
+        std::fs::write(yaml.join("index.yaml"), "\
+name: text
+spells:
+  - trigger: upper
+    description: Uppercase
+    category: text
+    aliases: [up]
+    entry:
+      default: tr a-z A-Z
+      env:
+        LC_ALL: C
+      args: [-v]
+    settings:
+      outputMode: clipboard
+      timeoutMs: 500
+    priority: 2
+  - trigger: lower
+    entry:
+      default: tr A-Z a-z
+").unwrap();
+        let summary = |folder: &Path| {
+            let collection = load_collection(folder.to_path_buf(), &mut Vec::new()).unwrap();
+            collection.spells.into_iter()
+                .map(|s| {
+                    assert_eq!(s.output_mode == OutputMode::Clipboard, s.trigger != "lower");
+                    (s.trigger, s.primary_trigger, s.aliases, s.description, s.category, s.entry_cmd, s.env, s.args, s.timeout_ms, s.priority)
+                })
+                .collect::<Vec<_>>()
+        };
+        let from_json = summary(&json);
+        assert_eq!(from_json.len(), 3);
+        assert_eq!(summary(&yaml), from_json);
+    }
+
+    #[test]
+    fn one_trailing_line_ending_is_trimmed_whether_crlf_or_lf() {
+        assert_eq!(trim_line_ending("text\r\n"), "text");
+        assert_eq!(trim_line_ending("text\n"), "text");
+        assert_eq!(trim_line_ending("text"), "text");
+        // Interior line endings and further blank lines stay.
+        assert_eq!(trim_line_ending("a\r\nb\r\n"), "a\r\nb");
+        assert_eq!(trim_line_ending("text\n\n"), "text\n");
+        assert_eq!(trim_line_ending("text\r"), "text\r");
+    }
+
+    #[test]
+    fn several_megabytes_stream_through_cat_intact() {
+        let (_dir, spell) = one_spell("stream-cat", serde_json::json!({
+            "trigger": "cat",
+            "entry": { "default": "cat" },
+        }));
+        let input = "h\u{e9}llo w\u{f6}rld \u{1f600}\n".repeat(250_000);
+        assert!(input.len() > 4_000_000);
+        let (mut child, _image) = spawn_entry(&spell, &SpellInput::Text(input.clone()), false, None).ok().unwrap();
+        let rx = pipe_to_channel(child.stdout.take().unwrap());
+        // A deadlock shows up as a timeout rather than a hung test run.
+        let deadline = stream_deadline(Some(30_000));
+        let mut output = String::new();
+        let end = stream_batched(rx, deadline, std::time::Duration::from_millis(MIN_STREAM_FLUSH_MS), &AtomicBool::new(false), |chunk, _| {
+            output.push_str(chunk);
+        });
+        assert!(matches!(end, StreamEnd::Finished));
+        assert!(child.wait().unwrap().success());
+        assert_eq!(output.len(), input.len());
+        assert!(output == input);
+    }
 }
@@ -4,44 +4,63 @@ import { listen } from "@tauri-apps/api/event";
 interface SpellInfo {
   trigger: string;
   description: string | null;
+  category: string | null;
+  icon: string | null;
+  collection: string;
+  collection_path: string;
+  primary_trigger: string | null;
+  aliases: string[];
+  output_mode: string;
+  stream_mode: boolean;
+  run_count: number;
+  is_favorite: boolean;
+}
+
+interface ParamDef {
+  name: string;
+  prompt: string;
+  default: string | null;
+}
+
+interface LoadDiagnostic {
+  trigger: string;
+  first: string;
+  second: string;
 }
 
 type SpellResult =
   | { mode: "done" }
-  | { mode: "preview"; content: string }
-  | { mode: "stream" };
+  | { mode: "preview"; content: string; format: "plain" | "markdown" | "html" }
+  | { mode: "stream" }
+  | { mode: "busy" }
+  | { mode: "needs_confirm"; trigger: string };
+
+// The spell-stream-error payload.
+interface StreamError {
+  stdout: string;
+  stderr: string;
+  exit_code: number | null;
+}
+
+// What apply_spell and friends reject with.
+type SpellError =
+  | { mode: "not_found"; trigger: string }
+  | { mode: "execution_failed"; code: number | null; stderr: string; stdout: string }
+  | { mode: "timeout"; timeout_ms: number }
+  | { mode: "hook_failed" | "io_error" | "input_unavailable" | "invalid"; message: string };
 
-let spells: SpellInfo[] = [];
 let selectedIndex = 0;
-let recentTriggers: string[] = [];
+// A confirm spell waiting for Enter in the preview pane, with its params.
+let pendingConfirm: { trigger: string; params: Record<string, string> | null } | null = null;
 
 const MAX_RECENT = 5;
 
-function loadRecent() {
-  try {
-    const stored = localStorage.getItem("recentTriggers");
-    recentTriggers = stored ? JSON.parse(stored) : [];
-  } catch {
-    recentTriggers = [];
-  }
-}
-
-function saveRecent(trigger: string) {
-  recentTriggers = [trigger, ...recentTriggers.filter(t => t !== trigger)].slice(0, MAX_RECENT);
-  localStorage.setItem("recentTriggers", JSON.stringify(recentTriggers));
-}
-
-function getDisplayList(query: string): SpellInfo[] {
+// With nothing typed, show the spells used most often and most recently.
+async function getDisplayList(query: string): Promise<SpellInfo[]> {
   if (query === "") {
-    const recent = recentTriggers
-      .map(t => spells.find(s => s.trigger === t))
-      .filter((s): s is SpellInfo => s !== undefined);
-    return recent;
+    return invoke<SpellInfo[]>("get_recent_spells", { limit: MAX_RECENT });
   }
-  return spells.filter(
-    s => s.trigger.toLowerCase().includes(query) ||
-         (s.description?.toLowerCase().includes(query) ?? false)
-  );
+  return invoke<SpellInfo[]>("search_spells", { query });
 }
 
 // ---- State management ----
@@ -57,6 +76,7 @@ function showPreview(streaming: boolean) {
   document.getElementById("selector")!.style.display = "none";
   const preview = document.getElementById("preview")!;
   preview.style.display = "flex";
+  document.getElementById("params-form")!.style.display = "none";
   document.getElementById("preview-label")!.textContent = streaming
     ? "Output (streaming…)"
     : "Output";
@@ -65,8 +85,35 @@ function showPreview(streaming: boolean) {
 // ---- Spell list ----
 
 async function loadSpells() {
-  spells = await invoke<SpellInfo[]>("get_spells");
-  renderSpells(getDisplayList(""));
+  renderSpells(await getDisplayList(""));
+  await refreshLoadErrors();
+}
+
+async function refreshLoadErrors() {
+  renderLoadErrors(
+    await invoke<[string, string][]>("get_load_errors"),
+    await invoke<LoadDiagnostic[]>("get_load_diagnostics"),
+  );
+}
+
+function renderLoadErrors(errors: [string, string][], diagnostics: LoadDiagnostic[]) {
+  const box = document.getElementById("load-errors")!;
+  box.innerHTML = "";
+  box.style.display = errors.length === 0 && diagnostics.length === 0 ? "none" : "block";
+  for (const [path, message] of errors) {
+    const name = path.split(/[\\/]/).pop() || path;
+    const line = document.createElement("div");
+    line.textContent = `Collection ${name}: ${message}`;
+    line.title = path;
+    box.appendChild(line);
+  }
+  for (const { trigger, first, second } of diagnostics) {
+    const line = document.createElement("div");
+    line.textContent = first === second
+      ? `Trigger "${trigger}" is defined twice in ${first}`
+      : `Trigger "${trigger}" is defined in both ${first} and ${second}`;
+    box.appendChild(line);
+  }
 }
 
 function renderSpells(list: SpellInfo[]) {
@@ -87,16 +134,44 @@ function renderSpells(list: SpellInfo[]) {
 
     const trigger = document.createElement("span");
     trigger.className = "trigger";
-    trigger.textContent = spell.trigger;
+    // Image-path icons aren't rendered yet; emoji and other text go before the trigger.
+    trigger.textContent = spell.icon && !/[\\/]/.test(spell.icon)
+      ? `${spell.icon} ${spell.trigger}`
+      : spell.trigger;
+    // Paste is the default, so only the other modes get a badge.
+    if (spell.output_mode !== "paste" || spell.stream_mode) {
+      const badge = document.createElement("span");
+      badge.className = "badge";
+      badge.textContent = spell.stream_mode ? `${spell.output_mode} · stream` : spell.output_mode;
+      trigger.appendChild(badge);
+    }
     li.appendChild(trigger);
 
-    if (spell.description) {
+    const aliasNote = spell.aliases.length > 0 ? `also: ${spell.aliases.join(", ")}` : "";
+    if (spell.description || aliasNote) {
       const desc = document.createElement("span");
       desc.className = "desc";
-      desc.textContent = spell.description;
+      desc.textContent = [spell.description, aliasNote]
+        .filter(Boolean)
+        .join(" · ");
       li.appendChild(desc);
     }
 
+    const star = document.createElement("button");
+    star.className = "star";
+    const paintStar = () => {
+      star.classList.toggle("on", spell.is_favorite);
+      star.textContent = spell.is_favorite ? "★" : "☆";
+      star.title = spell.is_favorite ? "Remove from favorites" : "Add to favorites";
+    };
+    paintStar();
+    star.addEventListener("click", async (e) => {
+      e.stopPropagation();
+      spell.is_favorite = await invoke<boolean>("toggle_favorite", { trigger: spell.trigger });
+      paintStar();
+    });
+    li.appendChild(star);
+
     li.addEventListener("click", () => applySpell(spell.trigger));
     ul.appendChild(li);
   });
@@ -107,15 +182,105 @@ function updateSelection(list: NodeListOf<HTMLLIElement>, index: number) {
   list[index]?.scrollIntoView({ block: "nearest" });
 }
 
+function showError(message: string, code: number | null = null) {
+  showPreview(false);
+  document.getElementById("preview-label")!.textContent =
+    code === null ? "Error" : `Error (exit code ${code})`;
+  document.getElementById("preview-content")!.textContent = message;
+}
+
+function showSpellError(error: SpellError) {
+  if (error.mode === "not_found") {
+    showError(`Spell "${error.trigger}" not found`);
+  } else if (error.mode === "execution_failed") {
+    showError(
+      error.stdout ? `${error.stderr}\n\n--- stdout ---\n${error.stdout}` : error.stderr,
+      error.code,
+    );
+  } else if (error.mode === "timeout") {
+    showError(`Spell timed out after ${error.timeout_ms} ms`);
+  } else if (error.mode === "input_unavailable") {
+    showError(`${error.message}\n\nSpellpaste needs permission to simulate keystrokes (Accessibility on macOS, an X11 session on Linux).`);
+  } else {
+    showError(error.message);
+  }
+}
+
 async function applySpell(trigger: string) {
-  saveRecent(trigger);
-  const result = await invoke<SpellResult>("apply_spell", { trigger });
+  let params: ParamDef[];
+  try {
+    params = await invoke<ParamDef[]>("get_spell_params", { trigger });
+  } catch (e) {
+    showError(String(e));
+    return;
+  }
+  if (params.length > 0) {
+    showParamsForm(trigger, params);
+  } else {
+    await runSpell("apply_spell", trigger);
+  }
+}
+
+// Asks for the spell's params in the preview pane; Enter runs it.
+function showParamsForm(trigger: string, params: ParamDef[]) {
+  showPreview(false);
+  document.getElementById("preview-label")!.textContent = trigger;
+  document.getElementById("preview-content")!.textContent = "";
+  const form = document.getElementById("params-form") as HTMLFormElement;
+  form.replaceChildren();
+  for (const param of params) {
+    const label = document.createElement("label");
+    label.textContent = param.prompt;
+    const input = document.createElement("input");
+    input.name = param.name;
+    input.value = param.default ?? "";
+    input.autocomplete = "off";
+    label.appendChild(input);
+    form.appendChild(label);
+  }
+  const submit = document.createElement("button");
+  submit.type = "submit";
+  submit.textContent = "Run";
+  form.appendChild(submit);
+  form.onsubmit = (e) => {
+    e.preventDefault();
+    const values: Record<string, string> = {};
+    new FormData(form).forEach((value, name) => { values[name] = String(value); });
+    runSpell("apply_spell_with_params", trigger, values);
+  };
+  form.style.display = "flex";
+  form.querySelector("input")?.focus();
+}
+
+async function runSpell(
+  command: "apply_spell" | "apply_spell_with_params" | "confirm_spell",
+  trigger: string,
+  params: Record<string, string> | null = null,
+) {
+  pendingConfirm = null;
+  let result: SpellResult;
+  try {
+    result = await invoke<SpellResult>(command, params ? { trigger, params } : { trigger });
+  } catch (e) {
+    showSpellError(e as SpellError);
+    return;
+  }
   if (result.mode === "preview") {
-    document.getElementById("preview-content")!.textContent = result.content;
+    const content = document.getElementById("preview-content")!;
+    content.textContent = result.content;
+    content.dataset.format = result.format;
     showPreview(false);
   } else if (result.mode === "stream") {
     document.getElementById("preview-content")!.textContent = "";
     showPreview(true);
+  } else if (result.mode === "busy") {
+    showError("Another spell is still running");
+  } else if (result.mode === "needs_confirm") {
+    showPreview(false);
+    document.getElementById("preview-label")!.textContent = "Confirm";
+    document.getElementById("preview-content")!.textContent =
+      `Run "${result.trigger}"? Press Enter to run it, Esc to cancel.`;
+    pendingConfirm = { trigger: result.trigger, params };
   }
   // mode === "done": window is already hiding/hidden, nothing to do
 }
@@ -143,17 +308,83 @@ window.addEventListener("DOMContentLoaded", async () => {
     document.getElementById("preview-label")!.textContent = "Output";
   });
 
-  loadRecent();
-  loadSpells();
+  await listen<string>("spell-stream-stderr", (event) => {
+    const content = document.getElementById("preview-content")!;
+    content.textContent += event.payload;
+    content.scrollTop = content.scrollHeight;
+  });
+
+  await listen<number>("spell-stream-timeout", (event) => {
+    document.getElementById("preview-label")!.textContent =
+      `Timed out after ${event.payload} ms`;
+  });
+
+  // Preview streams have already shown stdout and stderr as they arrived, so
+  // stderr is only added when it isn't on screen yet (type streams).
+  await listen<StreamError>("spell-stream-error", (event) => {
+    const { stderr, exit_code } = event.payload;
+    const content = document.getElementById("preview-content")!;
+    if (!content.textContent?.includes(stderr)) {
+      if (content.textContent) content.textContent += "\n";
+      content.textContent += stderr;
+    }
+    document.getElementById("preview-label")!.textContent =
+      exit_code === null ? "Error" : `Error (exit code ${exit_code})`;
+  });
+
+  // A one-shot spell that takes a while: show it running, with any status
+  // lines it prints to stderr. The result replaces this when it arrives.
+  await listen<string>("spell-running", (event) => {
+    showPreview(false);
+    document.getElementById("preview-label")!.textContent = `Running ${event.payload}…`;
+    document.getElementById("preview-content")!.textContent = "";
+  });
+
+  await listen<string>("spell-progress", (event) => {
+    const content = document.getElementById("preview-content")!;
+    if (content.textContent) content.textContent += "\n";
+    content.textContent += event.payload;
+  });
+
+  // A failed before/after hook doesn't stop the spell unless it asks to, so
+  // the message is added to whatever the preview is showing.
+  await listen<string>("spell-hook-error", (event) => {
+    const content = document.getElementById("preview-content")!;
+    if (content.textContent) content.textContent += "\n";
+    content.textContent += event.payload;
+  });
+
   const search = document.getElementById("search") as HTMLInputElement;
+
+  // Reloads emit their warnings; the startup load's are fetched once.
+  await listen<string[]>("spell-load-warning", (event) => {
+    for (const warning of event.payload) console.warn(`Duplicate trigger ${warning}`);
+  });
+  for (const warning of await invoke<string[]>("get_load_warnings")) {
+    console.warn(`Duplicate trigger ${warning}`);
+  }
+
+  await listen<null>("spells-refreshed", async () => {
+    renderSpells(await getDisplayList(search.value));
+    await refreshLoadErrors();
+  });
+
+  loadSpells();
   search.focus();
 
-  document.getElementById("preview-close")!.addEventListener("click", () => {
+  // Closing the preview also stops a stream that is still producing output.
+  const closePreview = () => {
+    invoke("cancel_stream");
     invoke("cancel");
-  });
+  };
+
+  document.getElementById("preview-close")!.addEventListener("click", closePreview);
 
-  search.addEventListener("input", () => {
-    renderSpells(getDisplayList(search.value.toLowerCase()));
+  search.addEventListener("input", async () => {
+    const query = search.value;
+    const list = await getDisplayList(query);
+    // Drop results for a query the user has already typed past.
+    if (search.value === query) renderSpells(list);
   });
 
   document.addEventListener("keydown", (e) => {
@@ -161,7 +392,12 @@ window.addEventListener("DOMContentLoaded", async () => {
       document.getElementById("preview")!.style.display !== "none";
 
     if (previewVisible) {
-      if (e.key === "Escape") invoke("cancel");
+      if (e.key === "Escape") {
+        pendingConfirm = null;
+        closePreview();
+      } else if (e.key === "Enter" && pendingConfirm !== null) {
+        runSpell("confirm_spell", pendingConfirm.trigger, pendingConfirm.params);
+      }
       return;
     }
 
@@ -27,5 +27,18 @@ fn main() {
     println!("  result: {r:?}");
     sleep(Duration::from_millis(500));
 
+    // Test 4: multiple newlines, one line at a time with Return in between
+    // (the workaround the app's type_text uses)
+    println!("Test 4: multiple newlines, typed line by line");
+    for (i, line) in "i= 0\ni= 1\ni= 2\ni= 3\ni= 4".split('\n').enumerate() {
+        if i > 0 {
+            let r = enigo.key(Key::Return, Direction::Click);
+            println!("  return: {r:?}");
+        }
+        let r = enigo.text(line);
+        println!("  {line:?}: {r:?}");
+    }
+    sleep(Duration::from_millis(500));
+
     println!("Done.");
 }